@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 #[cfg(target_os = "android")]
 use android_logger::Config;
 use anyhow::Result;
@@ -5,7 +7,15 @@ use clap::Parser;
 #[cfg(target_os = "android")]
 use log::LevelFilter;
 
-use crate::{defs, event, module, supercall, utils};
+use crate::{
+    defs, event, magic_mount, module, module_backup, module_gc, module_history,
+    module_image::{self, ImageFs},
+    module_manifest, module_pending, module_profile,
+    module_signature::{self, SignaturePolicy},
+    module_verify, mount_journal, restorecon,
+    sepolicy::{DumpFormat, PolicyBackendKind, Risk},
+    supercall, utils,
+};
 
 /// APatch cli
 #[derive(Parser, Debug)]
@@ -47,14 +57,168 @@ enum Commands {
         #[command(subcommand)]
         command: Sepolicy,
     },
+
+    /// Manage the mounts made by magic mount
+    MagicMount {
+        #[command(subcommand)]
+        command: MagicMount,
+    },
+
+    /// Show which mounts belong to which module
+    Mounts,
+
+    /// Inspect the SELinux labels of module files
+    Restorecon {
+        #[command(subcommand)]
+        command: Restorecon,
+    },
+
+    /// Install, upgrade, uninstall and toggle modules to match a manifest
+    Apply {
+        /// manifest file
+        manifest: PathBuf,
+
+        /// only print the plan
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Write the installed modules as a manifest
+    Export {
+        /// manifest file to write, config files are copied next to it
+        output: PathBuf,
+
+        /// also pack every module as a zip next to the manifest, except modules with special
+        /// files such as device nodes or whiteouts
+        #[arg(long)]
+        with_sources: bool,
+    },
+
+    /// Remove module leftovers no installed module uses, orphaned config files are only listed
+    Gc {
+        /// only list what would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Switch between named sets of enabled modules
+    Profile {
+        #[command(subcommand)]
+        command: Profile,
+    },
+
+    /// Manage the keys module zips are signed with
+    Trust {
+        #[command(subcommand)]
+        command: Trust,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Profile {
+    /// Save the modules enabled now as profile <name>
+    Save {
+        /// profile name
+        name: String,
+    },
+
+    /// Enable the modules of profile <name> and disable all others
+    Apply {
+        /// profile name
+        name: String,
+    },
+
+    /// List profiles, `*` marks the applied ones
+    List,
+
+    /// Delete profile <name>
+    Delete {
+        /// profile name
+        name: String,
+    },
+
+    /// Show whether profile <name> is applied
+    Status {
+        /// profile name
+        name: String,
+
+        /// print whether a reboot is needed before the profile is active
+        #[arg(long)]
+        reboot_needed: bool,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Trust {
+    /// Trust an ed25519 public key
+    Add {
+        /// key name
+        name: String,
+
+        /// hex public key, or a file holding it
+        key: String,
+    },
+
+    /// Stop trusting a key
+    Remove {
+        /// key name
+        name: String,
+    },
+
+    /// List the trusted keys and the policy
+    List,
+
+    /// Show the signature status of a module zip
+    Check {
+        /// module zip
+        zip: PathBuf,
+    },
+
+    /// Show or set how unsigned and untrusted module zips are handled
+    Policy {
+        #[arg(value_enum)]
+        policy: Option<SignaturePolicy>,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Restorecon {
+    /// Report mislabeled and unlabeled module files without changing them
+    Check,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum MagicMount {
+    /// Detach recorded mounts without rebooting
+    Unmount {
+        /// only the mounts of this module
+        #[arg(short, long)]
+        module: Option<String>,
+    },
+
+    /// Count the mounts the enabled modules need, before and after whole dir binding
+    Plan {
+        /// modules directory to plan for
+        #[arg(long, default_value = defs::MODULE_DIR)]
+        modules: PathBuf,
+
+        /// root of the tree the modules are mounted on, a test tree instead of the system
+        #[arg(long, default_value = "/")]
+        root: PathBuf,
+    },
 }
 
 #[derive(clap::Subcommand, Debug)]
 enum Module {
     /// Install module <ZIP>
     Install {
-        /// module zip file path
-        zip: String,
+        /// module zips, module directories, or `-` to read a zip from stdin
+        #[arg(required = true)]
+        zips: Vec<String>,
+
+        /// keep installing the remaining modules when one fails
+        #[arg(long)]
+        continue_on_error: bool,
     },
 
     /// Uninstall module <id>
@@ -75,6 +239,18 @@ enum Module {
         id: String,
     },
 
+    /// mount module <id> now, when no reboot is needed for it
+    Apply {
+        /// module id
+        id: String,
+    },
+
+    /// unmount module <id> now, when no reboot is needed for it
+    Unapply {
+        /// module id
+        id: String,
+    },
+
     /// run action for module <id>
     Action {
         // module id
@@ -89,6 +265,114 @@ enum Module {
     },
     /// list all modules
     List,
+
+    /// Archive modules with their state and config files
+    Backup {
+        /// module ids
+        #[arg(required_unless_present = "all")]
+        ids: Vec<String>,
+
+        /// back up every installed module
+        #[arg(long, conflicts_with = "ids")]
+        all: bool,
+
+        /// archive to write
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+
+    /// Stage the modules of a backup archive for the next boot
+    Restore {
+        /// backup archive
+        archive: PathBuf,
+
+        /// only these modules of the archive
+        ids: Vec<String>,
+    },
+
+    /// List what the next boot will change
+    Pending {
+        /// print the changes as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Keep module <id> which is pending removal
+    RestorePending {
+        /// module id
+        id: String,
+    },
+
+    /// Drop the staged update of module <id>
+    DiscardUpdate {
+        /// module id
+        id: String,
+    },
+
+    /// Check a module zip without installing it
+    Verify {
+        /// module zip
+        zip: PathBuf,
+
+        /// print the report as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// the zip is a module backup, only its entries are checked
+        #[arg(long)]
+        backup: bool,
+    },
+
+    /// List the kept older versions of a module
+    History {
+        /// module id
+        id: String,
+    },
+
+    /// Stage an older version of a module for next boot
+    Rollback {
+        /// module id
+        id: String,
+
+        /// versionCode to go back to, the latest replaced version by default
+        #[arg(long)]
+        to: Option<String>,
+    },
+
+    /// Manage the image modules are kept in
+    Image {
+        #[command(subcommand)]
+        command: ModuleImage,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum ModuleImage {
+    /// Show the storage mode and the image usage
+    Status,
+
+    /// Keep modules in an image from the next boot on
+    Enable {
+        /// image filesystem
+        #[arg(value_enum, default_value_t = ImageFs::Ext4)]
+        fs: ImageFs,
+
+        /// initial ext4 image size in MiB
+        #[arg(short, long)]
+        size: Option<u64>,
+    },
+
+    /// Move modules back to a plain directory on the next boot
+    Disable,
+
+    /// Resize the ext4 image, shrinking happens on the next boot
+    Resize {
+        /// new size in MiB
+        size: u64,
+    },
+
+    /// Check the image for errors
+    Fsck,
 }
 
 #[derive(clap::Subcommand, Debug)]
@@ -97,6 +381,76 @@ enum Sepolicy {
     Check {
         /// sepolicy statements
         sepolicy: String,
+
+        /// report every error and warning with its line and column
+        #[arg(short, long)]
+        diagnostics: bool,
+
+        /// skip invalid statements like at boot, reporting them as warnings, implies --diagnostics
+        #[arg(long)]
+        lenient: bool,
+
+        /// also report names missing from a compiled policy, the loaded one if no file is given
+        #[arg(long, num_args = 0..=1, default_missing_value = crate::policydb::LOADED_POLICY)]
+        against: Option<PathBuf>,
+    },
+
+    /// Apply sepolicy statements or a rule file to the running policy
+    Apply {
+        /// sepolicy statements or rule file
+        sepolicy: String,
+
+        /// how the statements are submitted
+        #[arg(short, long, value_enum, default_value_t = PolicyBackendKind::Auto)]
+        backend: PolicyBackendKind,
+    },
+
+    /// Report risky statements in sepolicy statements or a rule file
+    Lint {
+        /// sepolicy statements or rule file
+        sepolicy: String,
+    },
+
+    /// Show or set the lint risk at which module installation is refused
+    LintBlock {
+        /// refuse modules with findings at this risk or above
+        #[arg(value_enum)]
+        level: Option<Risk>,
+
+        /// never refuse modules because of lint findings
+        #[arg(long, conflicts_with = "level")]
+        off: bool,
+    },
+
+    /// Print the syntax tree of sepolicy statements or a rule file
+    Parse {
+        /// sepolicy statements or rule file
+        sepolicy: String,
+
+        /// emit JSON instead of the debug form
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Rewrite a rule file in canonical form
+    Fmt {
+        /// rule file
+        file: PathBuf,
+
+        /// write the result back to the file instead of printing it
+        #[arg(short, long)]
+        write: bool,
+
+        /// only fail if the file is not already formatted
+        #[arg(long, conflicts_with = "write")]
+        check: bool,
+    },
+
+    /// Print the merged rules of all active modules and which modules added them
+    Dump {
+        /// output format
+        #[arg(short, long, value_enum, default_value_t = DumpFormat::Text)]
+        format: DumpFormat,
     },
 }
 
@@ -143,20 +497,149 @@ pub fn run() -> Result<()> {
             {
                 utils::switch_mnt_ns(1)?;
             }
+            if !matches!(command, Module::Image { .. }) {
+                module_image::ensure_mounted()?;
+            }
             match command {
-                Module::Install { zip } => module::install_module(&zip),
+                Module::Install {
+                    zips,
+                    continue_on_error,
+                } => module::install_modules(&zips, continue_on_error),
                 Module::Uninstall { id } => module::uninstall_module(&id),
                 Module::Action { id } => module::run_action(&id),
                 Module::Lua { id, function } => module::run_lua(&id, &function, false, true)
                     .map_err(|e| anyhow::anyhow!("{}", e)),
                 Module::Enable { id } => module::enable_module(&id),
                 Module::Disable { id } => module::disable_module(&id),
+                Module::Apply { id } => magic_mount::apply_module(&id),
+                Module::Unapply { id } => mount_journal::unapply_module(&id),
                 Module::List => module::list_modules(),
+                Module::Backup { ids, all, output } => {
+                    module_backup::backup_modules(&ids, all, &output)
+                }
+                Module::Restore { archive, ids } => module_backup::restore_modules(&archive, &ids),
+                Module::Pending { json } => module_pending::pending(json),
+                Module::RestorePending { id } => module::restore_pending(&id),
+                Module::DiscardUpdate { id } => module::discard_update(&id),
+                Module::Verify { zip, json, backup } => module_verify::verify(&zip, json, backup),
+                Module::History { id } => module_history::list_history(&id),
+                Module::Rollback { id, to } => module_history::rollback(&id, to.as_deref()),
+                Module::Image { command } => match command {
+                    ModuleImage::Status => module_image::status(),
+                    ModuleImage::Enable { fs, size } => module_image::enable(fs, size),
+                    ModuleImage::Disable => module_image::disable(),
+                    ModuleImage::Resize { size } => module_image::resize(size),
+                    ModuleImage::Fsck => module_image::fsck(),
+                },
             }
         }
 
         Commands::Sepolicy { command } => match command {
-            Sepolicy::Check { sepolicy } => crate::sepolicy::check_rule(&sepolicy),
+            Sepolicy::Check {
+                sepolicy,
+                diagnostics,
+                lenient,
+                against,
+            } => {
+                if diagnostics || lenient || against.is_some() {
+                    crate::sepolicy::check_rule_diagnostics(&sepolicy, !lenient, against.as_deref())
+                } else {
+                    crate::sepolicy::check_rule(&sepolicy)
+                }
+            }
+            Sepolicy::Apply { sepolicy, backend } => {
+                crate::sepolicy::apply_rule(&sepolicy, backend, &cli.superkey)
+            }
+            Sepolicy::Lint { sepolicy } => crate::sepolicy::lint_rule(&sepolicy),
+            Sepolicy::LintBlock { level, off } => {
+                if off || level.is_some() {
+                    crate::sepolicy::set_lint_block_level(level)
+                } else {
+                    match crate::sepolicy::lint_block_level() {
+                        Some(level) => println!("{level}"),
+                        None => println!("off"),
+                    }
+                    Ok(())
+                }
+            }
+            Sepolicy::Parse { sepolicy, json } => crate::sepolicy::parse_rule(&sepolicy, json),
+            Sepolicy::Fmt { file, write, check } => {
+                crate::sepolicy::format_rule(&file, write, check)
+            }
+            Sepolicy::Dump { format } => module::dump_sepolicy_rule(format),
+        },
+
+        Commands::MagicMount { command } => match command {
+            MagicMount::Unmount { module } => mount_journal::unmount_modules(module.as_deref()),
+            MagicMount::Plan { modules, root } => magic_mount::plan(&modules, &root),
+        },
+
+        Commands::Mounts => mount_journal::list_mounts(),
+
+        Commands::Restorecon { command } => match command {
+            Restorecon::Check => restorecon::check(),
+        },
+
+        Commands::Apply { manifest, dry_run } => {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            {
+                utils::switch_mnt_ns(1)?;
+            }
+            module_image::ensure_mounted()?;
+            module_manifest::apply(&manifest, dry_run)
+        }
+
+        Commands::Export {
+            output,
+            with_sources,
+        } => {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            {
+                utils::switch_mnt_ns(1)?;
+            }
+            module_image::ensure_mounted()?;
+            module_manifest::export(&output, with_sources)
+        }
+
+        Commands::Gc { dry_run } => {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            {
+                utils::switch_mnt_ns(1)?;
+            }
+            module_image::ensure_mounted()?;
+            module_gc::gc(dry_run)
+        }
+
+        Commands::Profile { command } => {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            {
+                utils::switch_mnt_ns(1)?;
+            }
+            module_image::ensure_mounted()?;
+            match command {
+                Profile::Save { name } => module_profile::save(&name),
+                Profile::Apply { name } => module_profile::apply(&name),
+                Profile::List => module_profile::list(),
+                Profile::Delete { name } => module_profile::delete(&name),
+                Profile::Status {
+                    name,
+                    reboot_needed,
+                } => module_profile::status(&name, reboot_needed),
+            }
+        }
+
+        Commands::Trust { command } => match command {
+            Trust::Add { name, key } => module_signature::add_key(&name, &key),
+            Trust::Remove { name } => module_signature::remove_key(&name),
+            Trust::List => module_signature::list_keys(),
+            Trust::Check { zip } => module_signature::check(&zip),
+            Trust::Policy { policy } => match policy {
+                Some(policy) => module_signature::set_policy(policy),
+                None => {
+                    println!("{}", module_signature::policy());
+                    Ok(())
+                }
+            },
         },
 
         Commands::Services => event::on_services(cli.superkey),
@@ -6,12 +6,32 @@ pub const BINARY_DIR: &str = concatcp!(WORKING_DIR, "bin/");
 pub const APATCH_LOG_FOLDER: &str = concatcp!(WORKING_DIR, "log/");
 
 pub const AP_RC_PATH: &str = concatcp!(WORKING_DIR, ".aprc");
+pub const SEPOLICY_BATCH_FILE: &str = concatcp!(WORKING_DIR, ".sepolicy_batch.rule");
+pub const MOUNT_JOURNAL_FILE: &str = concatcp!(WORKING_DIR, "mount_journal");
+pub const RESTORECON_MANIFEST: &str = concatcp!(WORKING_DIR, "restorecon_manifest");
+pub const MODULE_STORAGE_FILE: &str = concatcp!(WORKING_DIR, "module_storage");
+pub const MODULE_IMAGE_FILE: &str = concatcp!(WORKING_DIR, "modules.img");
+pub const MODULE_IMAGE_RESIZE_FILE: &str = concatcp!(WORKING_DIR, "modules.img.resize");
+pub const MODULE_EROFS_FILE: &str = concatcp!(WORKING_DIR, "modules.erofs");
+pub const MODULE_IMAGE_WORK_DIR: &str = concatcp!(WORKING_DIR, "modules_image/");
+pub const MODULE_HISTORY_DIR: &str = concatcp!(WORKING_DIR, "module_history/");
+pub const SIGNATURE_POLICY_FILE: &str = concatcp!(WORKING_DIR, "signature_policy");
+pub const TRUSTED_KEYS_DIR: &str = concatcp!(WORKING_DIR, "trusted_keys/");
+pub const MODULE_PENDING_STATE_DIR: &str = concatcp!(WORKING_DIR, "pending_state/");
+pub const BOOT_STATE_FILE: &str = concatcp!(WORKING_DIR, "boot_state");
+pub const PROFILE_DIR: &str = concatcp!(WORKING_DIR, "profiles/");
+pub const PARTITIONS_CONFIG: &str = concatcp!(WORKING_DIR, "partitions.conf");
+pub const SEPOLICY_LINT_BLOCK_FILE: &str = concatcp!(WORKING_DIR, "sepolicy_lint_block");
 pub const GLOBAL_NAMESPACE_FILE: &str = concatcp!(ADB_DIR, ".global_namespace_enable");
 pub const MAGIC_MOUNT_FILE: &str = concatcp!(ADB_DIR, ".magic_mount_enable");
+pub const OVERLAYFS_FILE: &str = concatcp!(ADB_DIR, ".overlayfs_enable");
 pub const DAEMON_PATH: &str = concatcp!(ADB_DIR, "apd");
 
 pub const MODULE_DIR: &str = concatcp!(ADB_DIR, "modules/");
+// files the lua modules keep through setConfig/getConfig
+pub const MODULE_CONFIG_DIR: &str = concatcp!(ADB_DIR, "config/");
 pub const AP_MAGIC_MOUNT_SOURCE: &str = concatcp!(WORKING_DIR, "magic_mount");
+pub const AP_OVERLAY_SOURCE: &str = "APatch";
 
 // warning: this directory should not change, or you need to change the code in module_installer.sh!!!
 pub const MODULE_UPDATE_DIR: &str = concatcp!(ADB_DIR, "modules_update/");
@@ -25,6 +45,7 @@ pub const DISABLE_FILE_NAME: &str = "disable";
 pub const SKIP_MOUNT_FILE_NAME: &str = "skip_mount";
 pub const UPDATE_FILE_NAME: &str = "update";
 pub const REMOVE_FILE_NAME: &str = "remove";
+pub const SIGNATURE_STATUS_FILE_NAME: &str = ".signature";
 
 // Metamodule support
 pub const METAMODULE_MOUNT_SCRIPT: &str = "metamount.sh";
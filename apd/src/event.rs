@@ -20,7 +20,7 @@ use notify::{
 use signal_hook::{consts::signal::*, iterator::Signals};
 
 use crate::{
-    assets, defs, metamodule, module, restorecon, supercall,
+    assets, defs, metamodule, module, module_image, module_pending, restorecon, supercall,
     supercall::{
         fork_for_result, init_load_package_uid_config, init_load_su_path, refresh_ap_package_list,
     },
@@ -133,12 +133,19 @@ pub fn on_post_data_fs(superkey: Option<String>) -> Result<()> {
     }
     let module_update_dir = defs::MODULE_UPDATE_DIR; //save module place
     let module_dir = defs::MODULE_DIR; // run modules place
-    let module_update_flag = Path::new(defs::WORKING_DIR).join(defs::UPDATE_FILE_NAME); // if update ,there will be renewed modules file
     assets::ensure_binaries().with_context(|| "binary missing")?;
 
+    if let Err(e) = module_image::mount_modules_image() {
+        log::error!("mount module image failed: {e:#}");
+    }
+
     if Path::new(defs::MODULE_UPDATE_DIR).exists() {
+        if let Err(e) = module_image::reserve(utils::dir_size(module_update_dir)) {
+            warn!("reserve module image space failed: {e:#}");
+        }
         module::handle_updated_modules()?;
-        fs::remove_dir_all(module_update_dir)?;
+    } else if Path::new(defs::MODULE_PENDING_STATE_DIR).exists() {
+        let _ = fs::remove_dir_all(defs::MODULE_PENDING_STATE_DIR);
     }
 
     if safe_mode {
@@ -146,22 +153,36 @@ pub fn on_post_data_fs(superkey: Option<String>) -> Result<()> {
         if let Err(e) = module::disable_all_modules() {
             warn!("disable all modules failed: {}", e);
         }
+        if let Err(e) = module_pending::save_boot_state() {
+            warn!("save boot state failed: {e}");
+        }
         return Ok(());
     }
 
     if let Err(e) = module::prune_modules() {
         warn!("prune modules failed: {}", e);
     }
+    if let Err(e) = module_pending::save_boot_state() {
+        warn!("save boot state failed: {e}");
+    }
 
     if let Err(e) = restorecon::restorecon() {
         warn!("restorecon failed: {}", e);
     }
 
     // load sepolicy.rule
-    if module::load_sepolicy_rule().is_err() {
-        warn!("load sepolicy.rule failed");
+    if let Err(e) = module::load_sepolicy_rule(&superkey) {
+        warn!("load sepolicy.rule failed: {e}");
     }
-    if Path::new(defs::MAGIC_MOUNT_FILE).exists() {
+    if Path::new(defs::OVERLAYFS_FILE).exists() {
+        info!("OverlayFS mode enabled");
+        if let Err(e) = crate::overlayfs::mount_modules() {
+            log::error!("OverlayFS mount failed: {e:#}, falling back to Magic Mount");
+            if let Err(e) = crate::magic_mount::magic_mount() {
+                log::error!("Magic Mount failed: {}", e);
+            }
+        }
+    } else if Path::new(defs::MAGIC_MOUNT_FILE).exists() {
         info!("Magic Mount mode enabled");
         if let Err(e) = crate::magic_mount::magic_mount() {
             log::error!("Magic Mount failed: {}", e);
@@ -187,8 +208,11 @@ pub fn on_post_data_fs(superkey: Option<String>) -> Result<()> {
         warn!("load system.prop failed: {}", e);
     }
 
-    info!("remove update flag");
-    let _ = fs::remove_file(module_update_flag);
+    // updates which could not be applied are still staged
+    info!("refresh update flag");
+    if let Err(e) = module::refresh_update_marker() {
+        warn!("refresh update flag failed: {e:#}");
+    }
 
     run_stage("post-mount", superkey, true);
 
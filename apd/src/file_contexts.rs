@@ -0,0 +1,252 @@
+use std::{
+    fs::FileType,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use anyhow::Result;
+use jwalk::{Parallelism::Serial, WalkDir};
+use log::warn;
+use regex::Regex;
+
+use crate::{
+    partition,
+    restorecon::{SYSTEM_CON, lsetfilecon},
+};
+
+/// A module's own labels, in file_contexts syntax: `regex [type] context`
+pub const MODULE_FILE_CONTEXTS: &str = "file_contexts";
+
+/// Leave the file as it is
+const NO_CONTEXT: &str = "<<none>>";
+
+pub const DEVICE_FILE_CONTEXTS: [&str; 5] = [
+    "/system/etc/selinux/plat_file_contexts",
+    "/system_ext/etc/selinux/system_ext_file_contexts",
+    "/product/etc/selinux/product_file_contexts",
+    "/vendor/etc/selinux/vendor_file_contexts",
+    "/odm/etc/selinux/odm_file_contexts",
+];
+
+const REGEX_META_CHARS: &[char] = &['.', '^', '$', '?', '*', '+', '|', '[', '(', '{', '\\'];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SpecFileType {
+    File,
+    Dir,
+    Symlink,
+    Char,
+    Block,
+    Socket,
+    Fifo,
+}
+
+impl SpecFileType {
+    fn parse(flag: &str) -> Option<Self> {
+        Some(match flag {
+            "--" => SpecFileType::File,
+            "-d" => SpecFileType::Dir,
+            "-l" => SpecFileType::Symlink,
+            "-c" => SpecFileType::Char,
+            "-b" => SpecFileType::Block,
+            "-s" => SpecFileType::Socket,
+            "-p" => SpecFileType::Fifo,
+            _ => return None,
+        })
+    }
+
+    fn matches(self, file_type: FileType) -> bool {
+        match self {
+            SpecFileType::File => file_type.is_file(),
+            SpecFileType::Dir => file_type.is_dir(),
+            SpecFileType::Symlink => file_type.is_symlink(),
+            SpecFileType::Char => file_type.is_char_device(),
+            SpecFileType::Block => file_type.is_block_device(),
+            SpecFileType::Socket => file_type.is_socket(),
+            SpecFileType::Fifo => file_type.is_fifo(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Spec {
+    pattern: String,
+    // the literal prefix every match starts with, so most regexes are never compiled
+    stem: String,
+    exact: bool,
+    file_type: Option<SpecFileType>,
+    context: String,
+    regex: OnceLock<Option<Regex>>,
+}
+
+impl Spec {
+    fn parse(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (pattern, file_type, context) = match fields[..] {
+            [pattern, context] => (pattern, None, context),
+            [pattern, flag, context] => (pattern, Some(SpecFileType::parse(flag)?), context),
+            _ => return None,
+        };
+        if context != NO_CONTEXT && context.split(':').count() < 4 {
+            return None;
+        }
+        let stem_len = pattern.find(REGEX_META_CHARS).unwrap_or(pattern.len());
+        Some(Spec {
+            pattern: pattern.to_string(),
+            stem: pattern[..stem_len].to_string(),
+            exact: stem_len == pattern.len(),
+            file_type,
+            context: context.to_string(),
+            regex: OnceLock::new(),
+        })
+    }
+
+    fn matches(&self, path: &str, file_type: FileType) -> bool {
+        if !path.starts_with(&self.stem) || self.file_type.is_some_and(|t| !t.matches(file_type)) {
+            return false;
+        }
+        if self.exact {
+            return path == self.pattern;
+        }
+        let regex = self.regex.get_or_init(|| {
+            Regex::new(&format!("^(?:{})$", self.pattern))
+                .inspect_err(|e| warn!("invalid file_contexts regex {}: {e}", self.pattern))
+                .ok()
+        });
+        regex.as_ref().is_some_and(|regex| regex.is_match(path))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FileContexts {
+    specs: Vec<Spec>,
+}
+
+impl FileContexts {
+    fn extend_from(&mut self, content: &str, origin: &Path) {
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match Spec::parse(line) {
+                Some(spec) => self.specs.push(spec),
+                None => warn!("{}: invalid line: {line}", origin.display()),
+            }
+        }
+    }
+
+    pub fn load(path: &Path) -> Option<Self> {
+        let content = std::fs::read_to_string(path).ok()?;
+        let mut contexts = FileContexts::default();
+        contexts.extend_from(&content, path);
+        Some(contexts)
+    }
+
+    /// The file_contexts of every partition of the device, in the order init loads them
+    pub fn load_device() -> Self {
+        let mut contexts = FileContexts::default();
+        for path in DEVICE_FILE_CONTEXTS.iter().map(Path::new) {
+            if let Ok(content) = std::fs::read_to_string(path) {
+                contexts.extend_from(&content, path);
+            }
+        }
+        contexts
+    }
+
+    /// The last matching spec wins, but an exact path beats any regex
+    pub fn lookup(&self, path: &str, file_type: FileType) -> Option<&str> {
+        let exact = self.specs.iter().rev().filter(|spec| spec.exact);
+        let regexes = self.specs.iter().rev().filter(|spec| !spec.exact);
+        exact
+            .chain(regexes)
+            .find(|spec| spec.matches(path, file_type))
+            .map(|spec| spec.context.as_str())
+    }
+}
+
+/// Where a path below a module's system/ shows up on the device
+fn device_path(relative: &Path, separate: &[String]) -> PathBuf {
+    let first = relative.components().next();
+    let is_separate = first.is_some_and(|first| {
+        separate
+            .iter()
+            .any(|name| first.as_os_str() == name.as_str())
+    });
+    if is_separate {
+        Path::new("/").join(relative)
+    } else {
+        Path::new("/system").join(relative)
+    }
+}
+
+/// Labels a module's files should have, resolved by its own file_contexts, then the device's
+pub struct ModuleLabeler {
+    module: FileContexts,
+    device: FileContexts,
+    separate: Vec<String>,
+}
+
+impl ModuleLabeler {
+    /// None when the module ships no file_contexts and keeps the plain system_file labels
+    pub fn new(module_dir: &Path) -> Option<Self> {
+        let module = FileContexts::load(&module_dir.join(MODULE_FILE_CONTEXTS))?;
+        let separate = partition::partitions()
+            .into_iter()
+            .filter(|partition| partition.is_separate())
+            .map(|partition| partition.name)
+            .collect();
+        Some(ModuleLabeler {
+            module,
+            device: FileContexts::load_device(),
+            separate,
+        })
+    }
+
+    fn label_for(&self, device_path: &Path, file_type: FileType) -> &str {
+        let path = device_path.to_string_lossy();
+        self.module
+            .lookup(&path, file_type)
+            .or_else(|| self.device.lookup(&path, file_type))
+            .unwrap_or(SYSTEM_CON)
+    }
+
+    /// Every mountable file of the module with the label it should carry
+    pub fn walk(
+        &self,
+        module_dir: &Path,
+        mut f: impl FnMut(&Path, &str) -> Result<()>,
+    ) -> Result<()> {
+        let mut roots = vec![(module_dir.join("system"), PathBuf::new())];
+        for partition in partition::partitions() {
+            if let Some(dir) = partition::module_partition_dir(module_dir, &partition.name) {
+                roots.push((dir, PathBuf::from(partition.name)));
+            }
+        }
+        for (root, prefix) in roots {
+            if !root.is_dir() {
+                continue;
+            }
+            for dir_entry in WalkDir::new(&root)
+                .parallelism(Serial)
+                .into_iter()
+                .flatten()
+            {
+                let path = dir_entry.path();
+                let Ok(relative) = path.strip_prefix(&root) else {
+                    continue;
+                };
+                let device_path = device_path(&prefix.join(relative), &self.separate);
+                let label = self.label_for(&device_path, dir_entry.file_type());
+                if label != NO_CONTEXT {
+                    f(&path, label)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn label(&self, module_dir: &Path) -> Result<()> {
+        self.walk(module_dir, |path, label| lsetfilecon(path, label))
+    }
+}
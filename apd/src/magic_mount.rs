@@ -7,34 +7,43 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result, bail, ensure};
 use extattr::lgetxattr;
 use rustix::{
-    fs::{Gid, MetadataExt, Mode, Uid, chmod, chown},
+    fs::{CWD, FileType as RawFileType, Gid, MetadataExt, Mode, Uid, chmod, chown, mknodat},
     mount::{
-        MountFlags, MountPropagationFlags, UnmountFlags, mount, mount_bind, mount_change,
-        mount_move, unmount,
+        MountFlags, MountPropagationFlags, UnmountFlags, mount, mount_bind, mount_bind_recursive,
+        mount_change, mount_move, unmount,
     },
 };
 
 use crate::{
     defs::{
-        AP_MAGIC_MOUNT_SOURCE, DISABLE_FILE_NAME, MODULE_DIR, REMOVE_FILE_NAME,
+        AP_MAGIC_MOUNT_SOURCE, DISABLE_FILE_NAME, MODULE_DIR, MODULE_UPDATE_DIR, REMOVE_FILE_NAME,
         SKIP_MOUNT_FILE_NAME,
     },
-    magic_mount::NodeFileType::{Directory, RegularFile, Symlink, Whiteout},
+    magic_mount::NodeFileType::{
+        BlockDevice, CharDevice, Directory, Fifo, RegularFile, Socket, Symlink, Whiteout,
+    },
+    mount_journal::{MountOp, MountRecord, read_journal, write_journal},
+    partition,
     restorecon::{lgetfilecon, lsetfilecon},
     utils::{ensure_dir_exists, get_work_dir},
 };
 
-const REPLACE_DIR_FILE_NAME: &str = ".replace";
-const REPLACE_DIR_XATTR: &str = "trusted.overlay.opaque";
+pub const REPLACE_DIR_FILE_NAME: &str = ".replace";
+pub const REPLACE_DIR_XATTR: &str = "trusted.overlay.opaque";
 
 #[derive(PartialEq, Eq, Hash, Clone, Debug)]
 enum NodeFileType {
     RegularFile,
     Directory,
     Symlink,
+    Fifo,
+    Socket,
+    CharDevice,
+    BlockDevice,
+    // a char device numbered 0:0, like overlayfs, deletes the file
     Whiteout,
 }
 
@@ -46,10 +55,20 @@ impl NodeFileType {
             Directory
         } else if file_type.is_symlink() {
             Symlink
+        } else if file_type.is_fifo() {
+            Fifo
+        } else if file_type.is_socket() {
+            Socket
+        } else if file_type.is_block_device() {
+            BlockDevice
         } else {
-            Whiteout
+            CharDevice
         }
     }
+
+    fn is_device(&self) -> bool {
+        matches!(self, CharDevice | BlockDevice)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -114,6 +133,17 @@ impl Node {
         }
     }
 
+    fn new_module_dir(name: &str, path: &Path) -> Self {
+        Node {
+            name: name.to_string(),
+            file_type: Directory,
+            children: Default::default(),
+            module_path: Some(path.to_path_buf()),
+            replace: Self::dir_is_replace(path),
+            skip: false,
+        }
+    }
+
     fn new_module<S>(name: &S, entry: &DirEntry) -> Option<Self>
     where
         S: ToString,
@@ -143,13 +173,20 @@ impl Node {
     }
 }
 
-fn collect_module_files() -> Result<Option<Node>> {
-    let mut root = Node::new_root("");
-    let mut system = Node::new_root("system");
-    let module_root = Path::new(MODULE_DIR);
-    let mut has_file = false;
+/// Module directories which take part in mounting, skipping disabled, removed and skip_mount ones
+/// The flag which keeps a module from being mounted, if it has one
+fn mount_flag(module_dir: &Path) -> Option<&'static str> {
+    [DISABLE_FILE_NAME, REMOVE_FILE_NAME, SKIP_MOUNT_FILE_NAME]
+        .into_iter()
+        .find(|flag| module_dir.join(flag).exists())
+}
+
+pub fn mountable_modules() -> Result<Vec<PathBuf>> {
+    mountable_modules_in(Path::new(MODULE_DIR))
+}
 
-    log::debug!("begin collect module files: {}", module_root.display());
+fn mountable_modules_in(module_root: &Path) -> Result<Vec<PathBuf>> {
+    let mut modules = vec![];
 
     for entry in module_root.read_dir()?.flatten() {
         if !entry.file_type()?.is_dir() {
@@ -165,42 +202,57 @@ fn collect_module_files() -> Result<Option<Node>> {
             continue;
         }
 
-        if entry.path().join(DISABLE_FILE_NAME).exists()
-            || entry.path().join(REMOVE_FILE_NAME).exists()
-            || entry.path().join(SKIP_MOUNT_FILE_NAME).exists()
-        {
-            log::debug!("skipped module {id}, due to disable/remove/skip_mount");
+        if let Some(flag) = mount_flag(&entry.path()) {
+            log::debug!("skipped module {id}, due to {flag}");
             continue;
         }
 
-        let mod_system = entry.path().join("system");
+        modules.push(entry.path());
+    }
+    modules.sort();
+    Ok(modules)
+}
 
-        if !mod_system.is_dir() {
-            continue;
-        }
+/// The tree of module files, with the partitions which are separate below `root_dir` next to system
+fn collect_module_files(modules: &[PathBuf], root_dir: &Path) -> Result<Option<Node>> {
+    let mut root = Node::new_root("");
+    let mut system = Node::new_root("system");
+    let mut has_file = false;
+
+    log::debug!("begin collect module files: {MODULE_DIR}");
+
+    let partitions = partition::partitions_below(root_dir);
+    for module in modules {
+        let mod_system = module.join("system");
+
+        if mod_system.is_dir() {
+            log::debug!("collecting {}", module.display());
 
-        log::debug!("collecting {}", entry.path().display());
+            has_file |= system.collect_module_files(mod_system)?;
+        }
 
-        has_file |= system.collect_module_files(mod_system)?;
+        // a partition dir at the top of the module is merged into system/<partition>
+        for (partition, _) in &partitions {
+            let Some(dir) = partition::module_partition_dir(module, &partition.name) else {
+                continue;
+            };
+            log::debug!("collecting {}", dir.display());
+            let node = system
+                .children
+                .entry(partition.name.clone())
+                .or_insert_with(|| Node::new_module_dir(&partition.name, &dir));
+            if node.file_type != Directory {
+                log::warn!("{} conflicts with a non-directory", dir.display());
+                continue;
+            }
+            has_file |= node.collect_module_files(&dir)? || node.replace;
+        }
     }
 
     if has_file {
-        const BUILTIN_PARTITIONS: [(&str, bool); 5] = [
-            ("vendor", true),
-            ("system_ext", true),
-            ("product", true),
-            ("odm", false),
-            ("oem", false),
-        ];
-
-        for (partition, require_symlink) in BUILTIN_PARTITIONS {
-            let path_of_root = Path::new("/").join(partition);
-            let path_of_system = Path::new("/system").join(partition);
-            if path_of_root.is_dir() && (!require_symlink || path_of_system.is_symlink()) {
-                let name = partition.to_string();
-                if let Some(node) = system.children.remove(&name) {
-                    root.children.insert(name, node);
-                }
+        for (partition, _) in partitions.iter().filter(|(_, separate)| *separate) {
+            if let Some(node) = system.children.remove(&partition.name) {
+                root.children.insert(partition.name.clone(), node);
             }
         }
 
@@ -224,40 +276,160 @@ fn clone_symlink<Src: AsRef<Path>, Dst: AsRef<Path>>(src: Src, dst: Dst) -> Resu
     Ok(())
 }
 
+/// State of one magic mount pass
+struct MountPass {
+    journal: Vec<MountRecord>,
+    // only plan the mounts, leaving the system untouched
+    dry_run: bool,
+    // mirror stock dirs file by file and bind module dirs file by file, as before
+    legacy: bool,
+}
+
+impl MountPass {
+    fn new(dry_run: bool, legacy: bool) -> Self {
+        MountPass {
+            journal: vec![],
+            dry_run,
+            legacy,
+        }
+    }
+
+    /// Build the tmpfs skeleton, unless only planning
+    fn prepare(&self, f: impl FnOnce() -> Result<()>) -> Result<()> {
+        if self.dry_run { Ok(()) } else { f() }
+    }
+
+    fn bind(
+        &mut self,
+        op: MountOp,
+        source: &Path,
+        target: &Path,
+        path: &Path,
+        in_tmpfs: bool,
+    ) -> Result<()> {
+        if !self.dry_run {
+            if source.is_dir() {
+                // keep whatever is mounted below a whole stock dir
+                mount_bind_recursive(source, target)?;
+            } else {
+                mount_bind(source, target)?;
+            }
+        }
+        self.journal
+            .push(MountRecord::new(op, path, Some(source), in_tmpfs));
+        Ok(())
+    }
+}
+
+/// Whether every file below a module dir comes from that same module, so the dir can be bound
+/// as a whole
+fn owned_by_single_module(node: &Node, module_path: &Path) -> bool {
+    node.children.values().all(|child| {
+        child.file_type != Whiteout
+            // /data is nodev, device nodes have to be created in the skeleton
+            && !child.file_type.is_device()
+            && child
+                .module_path
+                .as_ref()
+                .is_some_and(|path| path.starts_with(module_path))
+            && owned_by_single_module(child, module_path)
+    })
+}
+
+/// A whole dir bind shows the module dir itself, so it only stands in for the skeleton when no
+/// `.replace` marker would show up and every dir the skeleton copies from the stock one has the
+/// same mode, owner and label already
+fn binds_like_skeleton(module_dir: &Path, real_dir: &Path) -> bool {
+    if module_dir.join(REPLACE_DIR_FILE_NAME).exists() {
+        return false;
+    }
+    if let Ok(real) = real_dir.metadata() {
+        let Ok(module) = module_dir.metadata() else {
+            return false;
+        };
+        let same = real.mode() & 0o7777 == module.mode() & 0o7777
+            && real.uid() == module.uid()
+            && real.gid() == module.gid()
+            && lgetfilecon(real_dir).ok() == lgetfilecon(module_dir).ok();
+        if !same {
+            return false;
+        }
+    }
+    let Ok(entries) = read_dir(module_dir) else {
+        return false;
+    };
+    entries
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+        .all(|entry| binds_like_skeleton(&entry.path(), &real_dir.join(entry.file_name())))
+}
+
+/// Device nodes can not be bound from /data, which is nodev, so they are created anew
+fn clone_device<Src: AsRef<Path>, Dst: AsRef<Path>>(src: Src, dst: Dst) -> Result<()> {
+    let metadata = src.as_ref().symlink_metadata()?;
+    let file_type = if metadata.file_type().is_block_device() {
+        RawFileType::BlockDevice
+    } else {
+        RawFileType::CharacterDevice
+    };
+    let mode = Mode::from_raw_mode(metadata.mode() & 0o7777);
+    mknodat(CWD, dst.as_ref(), file_type, mode, metadata.rdev())?;
+    chmod(dst.as_ref(), mode)?;
+    chown(
+        dst.as_ref(),
+        Some(Uid::from_raw(metadata.uid())),
+        Some(Gid::from_raw(metadata.gid())),
+    )?;
+    lsetfilecon(dst.as_ref(), lgetfilecon(src.as_ref())?.as_str())?;
+    Ok(())
+}
+
 fn mount_mirror<P: AsRef<Path>, WP: AsRef<Path>>(
     path: P,
     work_dir_path: WP,
     entry: &DirEntry,
+    pass: &mut MountPass,
 ) -> Result<()> {
     let path = path.as_ref().join(entry.file_name());
     let work_dir_path = work_dir_path.as_ref().join(entry.file_name());
     let file_type = entry.file_type()?;
 
-    if file_type.is_file() {
+    if file_type.is_file() || file_type.is_fifo() || file_type.is_socket() {
         log::debug!(
             "mount mirror file {} -> {}",
             path.display(),
             work_dir_path.display()
         );
-        fs::File::create(&work_dir_path)?;
-        mount_bind(&path, &work_dir_path)?;
+        pass.prepare(|| {
+            fs::File::create(&work_dir_path)?;
+            Ok(())
+        })?;
+        pass.bind(MountOp::Mirror, &path, &work_dir_path, &path, true)?;
     } else if file_type.is_dir() {
         log::debug!(
             "mount mirror dir {} -> {}",
             path.display(),
             work_dir_path.display()
         );
-        create_dir(&work_dir_path)?;
-        let metadata = entry.metadata()?;
-        chmod(&work_dir_path, Mode::from_raw_mode(metadata.mode()))?;
-        chown(
-            &work_dir_path,
-            Some(Uid::from_raw(metadata.uid())),
-            Some(Gid::from_raw(metadata.gid())),
-        )?;
-        lsetfilecon(&work_dir_path, lgetfilecon(&path)?.as_str())?;
+        pass.prepare(|| {
+            create_dir(&work_dir_path)?;
+            let metadata = entry.metadata()?;
+            chmod(&work_dir_path, Mode::from_raw_mode(metadata.mode()))?;
+            chown(
+                &work_dir_path,
+                Some(Uid::from_raw(metadata.uid())),
+                Some(Gid::from_raw(metadata.gid())),
+            )?;
+            lsetfilecon(&work_dir_path, lgetfilecon(&path)?.as_str())?;
+            Ok(())
+        })?;
+        if !pass.legacy {
+            // nothing below is touched by modules, so one mount mirrors the whole dir
+            pass.bind(MountOp::Mirror, &path, &work_dir_path, &path, true)?;
+            return Ok(());
+        }
         for entry in read_dir(&path)?.flatten() {
-            mount_mirror(&path, &work_dir_path, &entry)?;
+            mount_mirror(&path, &work_dir_path, &entry, pass)?;
         }
     } else if file_type.is_symlink() {
         log::debug!(
@@ -265,7 +437,14 @@ fn mount_mirror<P: AsRef<Path>, WP: AsRef<Path>>(
             path.display(),
             work_dir_path.display()
         );
-        clone_symlink(&path, &work_dir_path)?;
+        pass.prepare(|| clone_symlink(&path, &work_dir_path))?;
+    } else if file_type.is_char_device() || file_type.is_block_device() {
+        log::debug!(
+            "create mirror device {} -> {}",
+            path.display(),
+            work_dir_path.display()
+        );
+        pass.prepare(|| clone_device(&path, &work_dir_path))?;
     }
 
     Ok(())
@@ -276,14 +455,19 @@ fn do_magic_mount<P: AsRef<Path>, WP: AsRef<Path>>(
     work_dir_path: WP,
     current: Node,
     has_tmpfs: bool,
+    pass: &mut MountPass,
 ) -> Result<()> {
     let mut current = current;
     let path = path.as_ref().join(&current.name);
     let work_dir_path = work_dir_path.as_ref().join(&current.name);
     match current.file_type {
-        RegularFile => {
+        // fifos and sockets are bound too, so both ends meet at the module's inode
+        RegularFile | Fifo | Socket => {
             let target_path = if has_tmpfs {
-                fs::File::create(&work_dir_path)?;
+                pass.prepare(|| {
+                    fs::File::create(&work_dir_path)?;
+                    Ok(())
+                })?;
                 &work_dir_path
             } else {
                 &path
@@ -294,7 +478,7 @@ fn do_magic_mount<P: AsRef<Path>, WP: AsRef<Path>>(
                     module_path.display(),
                     work_dir_path.display()
                 );
-                mount_bind(module_path, target_path)?;
+                pass.bind(MountOp::Bind, module_path, target_path, &path, has_tmpfs)?;
             } else {
                 bail!("cannot mount root file {}!", path.display());
             }
@@ -306,19 +490,56 @@ fn do_magic_mount<P: AsRef<Path>, WP: AsRef<Path>>(
                     module_path.display(),
                     work_dir_path.display()
                 );
-                clone_symlink(module_path, &work_dir_path)?;
+                pass.prepare(|| clone_symlink(module_path, &work_dir_path))?;
             } else {
                 bail!("cannot mount root symlink {}!", path.display());
             }
         }
+        CharDevice | BlockDevice => {
+            let Some(module_path) = &current.module_path else {
+                bail!("cannot mount root device {}!", path.display());
+            };
+            ensure!(has_tmpfs, "device {} needs a tmpfs", path.display());
+            log::debug!(
+                "create module device {} -> {}",
+                module_path.display(),
+                work_dir_path.display()
+            );
+            pass.prepare(|| clone_device(module_path, &work_dir_path))?;
+        }
         Directory => {
+            // a replaced or new dir which only one module fills is bound as a whole
+            if !pass.legacy
+                && let Some(module_path) = current.module_path.clone()
+                && (current.replace || (has_tmpfs && !path.exists()))
+                && owned_by_single_module(&current, &module_path)
+                && binds_like_skeleton(&module_path, &path)
+            {
+                log::debug!(
+                    "mount module dir {} -> {}",
+                    module_path.display(),
+                    path.display()
+                );
+                let target_path = if has_tmpfs {
+                    pass.prepare(|| {
+                        create_dir_all(&work_dir_path)?;
+                        Ok(())
+                    })?;
+                    &work_dir_path
+                } else {
+                    &path
+                };
+                pass.bind(MountOp::Bind, &module_path, target_path, &path, has_tmpfs)?;
+                return Ok(());
+            }
+
             let mut create_tmpfs = !has_tmpfs && current.replace && current.module_path.is_some();
             if !has_tmpfs && !create_tmpfs {
                 for it in &mut current.children {
                     let (name, node) = it;
                     let real_path = path.join(name);
                     let need = match node.file_type {
-                        Symlink => true,
+                        Symlink | CharDevice | BlockDevice => true,
                         Whiteout => real_path.exists(),
                         _ => {
                             if let Ok(metadata) = real_path.symlink_metadata() {
@@ -353,7 +574,6 @@ fn do_magic_mount<P: AsRef<Path>, WP: AsRef<Path>>(
                     path.display(),
                     work_dir_path.display()
                 );
-                create_dir_all(&work_dir_path)?;
                 let (metadata, path) = if path.exists() {
                     (path.metadata()?, &path)
                 } else if let Some(module_path) = &current.module_path {
@@ -361,13 +581,17 @@ fn do_magic_mount<P: AsRef<Path>, WP: AsRef<Path>>(
                 } else {
                     bail!("cannot mount root dir {}!", path.display());
                 };
-                chmod(&work_dir_path, Mode::from_raw_mode(metadata.mode()))?;
-                chown(
-                    &work_dir_path,
-                    Some(Uid::from_raw(metadata.uid())),
-                    Some(Gid::from_raw(metadata.gid())),
-                )?;
-                lsetfilecon(&work_dir_path, lgetfilecon(path)?.as_str())?;
+                pass.prepare(|| {
+                    create_dir_all(&work_dir_path)?;
+                    chmod(&work_dir_path, Mode::from_raw_mode(metadata.mode()))?;
+                    chown(
+                        &work_dir_path,
+                        Some(Uid::from_raw(metadata.uid())),
+                        Some(Gid::from_raw(metadata.gid())),
+                    )?;
+                    lsetfilecon(&work_dir_path, lgetfilecon(path)?.as_str())?;
+                    Ok(())
+                })?;
             }
 
             if create_tmpfs {
@@ -376,7 +600,10 @@ fn do_magic_mount<P: AsRef<Path>, WP: AsRef<Path>>(
                     path.display(),
                     work_dir_path.display()
                 );
-                mount_bind(&work_dir_path, &work_dir_path).context("bind self")?;
+                pass.prepare(|| {
+                    mount_bind(&work_dir_path, &work_dir_path).context("bind self")?;
+                    Ok(())
+                })?;
             }
 
             if path.exists() && !current.replace {
@@ -386,10 +613,10 @@ fn do_magic_mount<P: AsRef<Path>, WP: AsRef<Path>>(
                         if node.skip {
                             continue;
                         }
-                        do_magic_mount(&path, &work_dir_path, node, has_tmpfs)
+                        do_magic_mount(&path, &work_dir_path, node, has_tmpfs, pass)
                             .with_context(|| format!("magic mount {}/{name}", path.display()))
                     } else if has_tmpfs {
-                        mount_mirror(&path, &work_dir_path, &entry)
+                        mount_mirror(&path, &work_dir_path, &entry, pass)
                             .with_context(|| format!("mount mirror {}/{name}", path.display()))
                     } else {
                         Ok(())
@@ -420,7 +647,7 @@ fn do_magic_mount<P: AsRef<Path>, WP: AsRef<Path>>(
                 if node.skip {
                     continue;
                 }
-                if let Err(e) = do_magic_mount(&path, &work_dir_path, node, has_tmpfs)
+                if let Err(e) = do_magic_mount(&path, &work_dir_path, node, has_tmpfs, pass)
                     .with_context(|| format!("magic mount {}/{name}", path.display()))
                 {
                     if has_tmpfs {
@@ -437,8 +664,18 @@ fn do_magic_mount<P: AsRef<Path>, WP: AsRef<Path>>(
                     work_dir_path.display(),
                     path.display()
                 );
-                mount_move(&work_dir_path, &path).context("move self")?;
-                mount_change(&path, MountPropagationFlags::PRIVATE).context("make self private")?;
+                pass.prepare(|| {
+                    mount_move(&work_dir_path, &path).context("move self")?;
+                    mount_change(&path, MountPropagationFlags::PRIVATE)
+                        .context("make self private")?;
+                    Ok(())
+                })?;
+                pass.journal.push(MountRecord::new(
+                    MountOp::Tmpfs,
+                    &path,
+                    current.module_path.as_deref(),
+                    false,
+                ));
             }
         }
         Whiteout => {
@@ -450,7 +687,7 @@ fn do_magic_mount<P: AsRef<Path>, WP: AsRef<Path>>(
 }
 
 pub fn magic_mount() -> Result<()> {
-    if let Some(root) = collect_module_files()? {
+    if let Some(root) = collect_module_files(&mountable_modules()?, Path::new("/"))? {
         log::debug!("collected: {:#?}", root);
         let tmp_dir = PathBuf::from(get_work_dir());
         ensure_dir_exists(&tmp_dir)?;
@@ -463,14 +700,172 @@ pub fn magic_mount() -> Result<()> {
         )
         .context("mount tmp")?;
         mount_change(&tmp_dir, MountPropagationFlags::PRIVATE).context("make tmp private")?;
-        let result = do_magic_mount("/", &tmp_dir, root, false);
+        let mut pass = MountPass::new(false, false);
+        let result = do_magic_mount("/", &tmp_dir, root, false, &mut pass);
+        log::info!("magic mount made {} mounts", pass.journal.len());
         if let Err(e) = unmount(&tmp_dir, UnmountFlags::DETACH) {
             log::error!("failed to unmount tmp {}", e);
         }
         fs::remove_dir(tmp_dir).ok();
+        // the work dir is covered by the tmpfs until now
+        if let Err(e) = write_journal(&pass.journal) {
+            log::error!("failed to write mount journal: {e:#}");
+        }
         result
     } else {
         log::info!("no modules to mount, skipping!");
         Ok(())
     }
 }
+
+fn count_ops(journal: &[MountRecord], op: MountOp) -> usize {
+    journal.iter().filter(|record| record.op == op).count()
+}
+
+/// Print how many mounts magic mount makes for the modules, with and without binding whole dirs
+pub fn plan(modules_dir: &Path, root: &Path) -> Result<()> {
+    let Some(node) = collect_module_files(&mountable_modules_in(modules_dir)?, root)? else {
+        println!("No module files to mount");
+        return Ok(());
+    };
+    let work_dir = Path::new(get_work_dir());
+    let mut journals = vec![];
+    for legacy in [true, false] {
+        let mut pass = MountPass::new(true, legacy);
+        do_magic_mount(root, work_dir, node.clone(), false, &mut pass)?;
+        journals.push(pass.journal);
+    }
+    let [legacy, optimised] = &journals[..] else {
+        unreachable!();
+    };
+    println!("{:<8} {:>8} {:>10}", "", "legacy", "optimised");
+    for (name, op) in [
+        ("tmpfs", MountOp::Tmpfs),
+        ("bind", MountOp::Bind),
+        ("mirror", MountOp::Mirror),
+    ] {
+        println!(
+            "{name:<8} {:>8} {:>10}",
+            count_ops(legacy, op),
+            count_ops(optimised, op)
+        );
+    }
+    println!("{:<8} {:>8} {:>10}", "total", legacy.len(), optimised.len());
+    Ok(())
+}
+
+/// Collect the bind mounts which put a module's files in place on the running system, or why
+/// that is not possible without the tmpfs skeletons only built at boot
+fn plan_live_binds(
+    path: &Path,
+    node: &Node,
+    binds: &mut Vec<(PathBuf, PathBuf)>,
+    refusals: &mut Vec<String>,
+) {
+    let path = path.join(&node.name);
+    let real = path.symlink_metadata();
+    match node.file_type {
+        RegularFile | Fifo | Socket => match (&node.module_path, real) {
+            (Some(module_path), Ok(metadata))
+                if NodeFileType::from_file_type(metadata.file_type()) == node.file_type =>
+            {
+                binds.push((module_path.clone(), path));
+            }
+            (_, Ok(_)) => refusals.push(format!("{} changes its file type", path.display())),
+            (_, Err(_)) => refusals.push(format!("{} is a new file", path.display())),
+        },
+        Symlink => refusals.push(format!("{} is a symlink", path.display())),
+        CharDevice | BlockDevice => refusals.push(format!("{} is a device node", path.display())),
+        Whiteout => refusals.push(format!("{} is removed", path.display())),
+        Directory => {
+            if node.replace {
+                refusals.push(format!("{} is a replace directory", path.display()));
+                return;
+            }
+            if !real.is_ok_and(|metadata| metadata.is_dir()) {
+                refusals.push(format!("{} is a new directory", path.display()));
+                return;
+            }
+            let mut children: Vec<&Node> = node.children.values().collect();
+            children.sort_by(|a, b| a.name.cmp(&b.name));
+            for child in children {
+                plan_live_binds(&path, child, binds, refusals);
+            }
+        }
+    }
+}
+
+/// Mount one module's files without a reboot, as long as plain bind mounts are enough
+pub fn apply_module(id: &str) -> Result<()> {
+    // a freshly installed or updated module still waits in modules_update
+    let update_dir = Path::new(MODULE_UPDATE_DIR).join(id);
+    let module_dir = if update_dir.join("module.prop").exists() {
+        update_dir
+    } else {
+        Path::new(MODULE_DIR).join(id)
+    };
+    ensure!(
+        module_dir.join("module.prop").exists(),
+        "module {id} not found"
+    );
+    // the state flags stay in the live dir while an update waits
+    let live_dir = Path::new(MODULE_DIR).join(id);
+    if let Some(flag) = mount_flag(&live_dir).or_else(|| mount_flag(&module_dir)) {
+        bail!("module {id} has the {flag} flag and is not mounted at boot either");
+    }
+
+    let Some(root) = collect_module_files(&[module_dir], Path::new("/"))? else {
+        println!("- Module {id} has nothing to mount");
+        return Ok(());
+    };
+    let mut binds = vec![];
+    let mut refusals = vec![];
+    plan_live_binds(Path::new("/"), &root, &mut binds, &mut refusals);
+
+    let journal = read_journal()?;
+    let (own, others): (Vec<MountRecord>, Vec<MountRecord>) = journal
+        .into_iter()
+        .partition(|record| record.module.as_deref() == Some(id));
+    if own
+        .iter()
+        .any(|record| record.in_tmpfs || record.op == MountOp::Tmpfs)
+    {
+        refusals.push("the module was mounted with a tmpfs skeleton at boot".to_string());
+    }
+    for (_, target) in &binds {
+        if let Some(other) = others.iter().find(|record| &record.target == target) {
+            refusals.push(format!(
+                "{} is already mounted by {}",
+                target.display(),
+                other.module.as_deref().unwrap_or("stock")
+            ));
+        }
+    }
+    if !refusals.is_empty() {
+        for refusal in &refusals {
+            println!("- {refusal}");
+        }
+        bail!("module {id} can not be applied live, reboot to apply it");
+    }
+
+    // an already applied version is replaced
+    for record in own.iter().rev() {
+        if let Err(e) = unmount(&record.target, UnmountFlags::DETACH) {
+            log::warn!("failed to unmount {}: {e}", record.target.display());
+        }
+    }
+    let mut records: Vec<MountRecord> = vec![];
+    for (source, target) in &binds {
+        log::debug!("bind {} -> {}", source.display(), target.display());
+        if let Err(e) = mount_bind(source, target) {
+            for record in records.iter().rev() {
+                unmount(&record.target, UnmountFlags::DETACH).ok();
+            }
+            write_journal(&others)?;
+            return Err(e).with_context(|| format!("bind {}", target.display()));
+        }
+        records.push(MountRecord::new(MountOp::Bind, target, Some(source), false));
+    }
+    println!("- Applied {} mounts of {id}", records.len());
+    write_journal(&[others, records].concat())
+}
@@ -3,10 +3,24 @@ mod assets;
 mod cli;
 mod defs;
 mod event;
+mod file_contexts;
 mod magic_mount;
 mod metamodule;
 mod module;
+mod module_backup;
+mod module_gc;
+mod module_history;
+mod module_image;
+mod module_manifest;
+mod module_pending;
+mod module_profile;
+mod module_signature;
+mod module_verify;
+mod mount_journal;
+mod overlayfs;
 mod package;
+mod partition;
+mod policydb;
 #[cfg(any(target_os = "linux", target_os = "android"))]
 mod pty;
 mod restorecon;
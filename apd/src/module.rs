@@ -3,6 +3,7 @@ use std::os::unix::{prelude::PermissionsExt, process::CommandExt};
 use std::{
     collections::HashMap,
     env::var as env_var,
+    ffi::OsString,
     fs::{self, remove_dir_all},
     io::Cursor,
     path::{Path, PathBuf},
@@ -23,7 +24,9 @@ use crate::utils::*;
 use crate::{
     assets,
     defs::{self, MODULE_DIR, MODULE_UPDATE_DIR},
-    metamodule, restorecon,
+    file_contexts::ModuleLabeler,
+    metamodule, module_history, module_image, module_signature, module_verify, restorecon,
+    sepolicy,
 };
 
 const INSTALLER_CONTENT: &str = include_str!("./installer.sh");
@@ -35,6 +38,9 @@ const INSTALL_MODULE_SCRIPT: &str = concatcp!(
     "exit 0",
     "\n"
 );
+// zips read from stdin or packed from a directory, kept until their install is done
+const INSTALL_STAGING_DIR: &str = concatcp!(defs::WORKING_DIR, "install/");
+const STDIN_SOURCE: &str = "-";
 
 #[derive(PartialEq, Eq)]
 pub enum ModuleType {
@@ -61,13 +67,45 @@ fn exec_install_script(module_file: &str, is_metamodule: bool) -> Result<()> {
     Ok(())
 }
 
+/// Drop what the boot applied from a staging dir, entries named in `kept` stay
+fn clean_staging(dir: &str, kept: &[OsString]) -> Result<()> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Ok(());
+    };
+    for entry in entries.flatten() {
+        if kept.contains(&entry.file_name()) {
+            continue;
+        }
+        let path = entry.path();
+        if path.is_dir() && !path.is_symlink() {
+            remove_dir_all(&path)?;
+        } else {
+            fs::remove_file(&path)?;
+        }
+    }
+    if kept.is_empty() {
+        fs::remove_dir(dir)?;
+    }
+    Ok(())
+}
+
+/// Move staged updates over the installed modules. An update which can't be applied stays
+/// staged with its saved live state, so `apd module pending` still lists it and it can be
+/// discarded or retried on the next boot
 pub fn handle_updated_modules() -> Result<()> {
     let modules_root = Path::new(MODULE_DIR);
+    let mut kept = vec![];
     foreach_module(ModuleType::Updated, |updated_module| {
         if !updated_module.is_dir() {
             return Ok(());
         }
 
+        if let Err(e) = module_image::ensure_storable(updated_module) {
+            warn!("keep update {} staged: {e:#}", updated_module.display());
+            kept.extend(updated_module.file_name().map(ToOwned::to_owned));
+            return Ok(());
+        }
+
         if let Some(name) = updated_module.file_name() {
             let module_dir = modules_root.join(name);
             let mut disabled = false;
@@ -76,9 +114,20 @@ pub fn handle_updated_modules() -> Result<()> {
                 // If the old module is disabled, we need to also disable the new one
                 disabled = module_dir.join(defs::DISABLE_FILE_NAME).exists();
                 removed = module_dir.join(defs::REMOVE_FILE_NAME).exists();
-                remove_dir_all(&module_dir)?;
+                if let Err(e) = module_history::snapshot_module(&module_dir) {
+                    warn!(
+                        "Failed to keep the old version of {}: {e:#}",
+                        module_dir.display()
+                    );
+                    // the installed version is the only copy, the update waits for the next boot
+                    if module_dir.exists() {
+                        warn!("keep update {} staged", updated_module.display());
+                        kept.push(name.to_owned());
+                        return Ok(());
+                    }
+                }
             }
-            std::fs::rename(updated_module, &module_dir)?;
+            move_dir(updated_module, &module_dir)?;
             if removed {
                 let path = module_dir.join(defs::REMOVE_FILE_NAME);
                 if let Err(e) = ensure_file_exists(&path) {
@@ -93,7 +142,8 @@ pub fn handle_updated_modules() -> Result<()> {
         }
         Ok(())
     })?;
-    Ok(())
+    clean_staging(MODULE_UPDATE_DIR, &kept)?;
+    clean_staging(defs::MODULE_PENDING_STATE_DIR, &kept)
 }
 
 /// Get common environment variables for script execution
@@ -118,7 +168,7 @@ pub fn get_common_script_envs() -> Vec<(&'static str, String)> {
 // we need to update the module state after the boot_completed
 // if someone(such as the module) install a module before the boot_completed
 // then it may cause some problems, just forbid it
-fn ensure_boot_completed() -> Result<()> {
+pub fn ensure_boot_completed() -> Result<()> {
     // ensure getprop sys.boot_completed == 1
     if getprop("sys.boot_completed").as_deref() != Some("1") {
         bail!("Android is Booting!");
@@ -126,10 +176,36 @@ fn ensure_boot_completed() -> Result<()> {
     Ok(())
 }
 
-fn mark_update() -> Result<()> {
+pub fn mark_update() -> Result<()> {
     ensure_file_exists(concatcp!(defs::WORKING_DIR, defs::UPDATE_FILE_NAME))
 }
 
+/// Keep the update marker only while a staged module or a pending removal is left
+pub fn refresh_update_marker() -> Result<()> {
+    let mut pending = false;
+    if Path::new(MODULE_UPDATE_DIR).exists() {
+        foreach_module(ModuleType::Updated, |_| {
+            pending = true;
+            Ok(())
+        })?;
+    }
+    if Path::new(MODULE_DIR).exists() {
+        foreach_module(ModuleType::All, |module| {
+            pending |= module.join(defs::REMOVE_FILE_NAME).exists();
+            Ok(())
+        })?;
+    }
+    let marker = Path::new(concatcp!(defs::WORKING_DIR, defs::UPDATE_FILE_NAME));
+    if pending {
+        mark_update()
+    } else {
+        if marker.exists() {
+            fs::remove_file(marker)?;
+        }
+        Ok(())
+    }
+}
+
 fn mark_module_state(module: &str, flag_file: &str, create_or_delete: bool) -> Result<()> {
     let module_state_file = Path::new(defs::MODULE_DIR).join(module).join(flag_file);
     if create_or_delete {
@@ -176,24 +252,27 @@ fn foreach_active_module(f: impl FnMut(&Path) -> Result<()>) -> Result<()> {
     foreach_module(ModuleType::Active, f)
 }
 
-pub fn load_sepolicy_rule() -> Result<()> {
+/// (module id, sepolicy.rule) of every active module that ships one
+fn active_sepolicy_rules() -> Result<Vec<(String, PathBuf)>> {
+    let mut rules = Vec::new();
     foreach_active_module(|path| {
         let rule_file = path.join("sepolicy.rule");
         if !rule_file.exists() {
             return Ok(());
         }
-
-        info!("load policy: {}", &rule_file.display());
-        Command::new(assets::MAGISKPOLICY_PATH)
-            .arg("--live")
-            .arg("--apply")
-            .arg(&rule_file)
-            .status()
-            .with_context(|| format!("Failed to exec {}", rule_file.display()))?;
+        let id = path.file_name().unwrap_or_default().to_string_lossy();
+        rules.push((id.to_string(), rule_file));
         Ok(())
     })?;
+    Ok(rules)
+}
 
-    Ok(())
+pub fn load_sepolicy_rule(superkey: &Option<String>) -> Result<()> {
+    sepolicy::apply_module_rules(&active_sepolicy_rules()?, superkey)
+}
+
+pub fn dump_sepolicy_rule(format: sepolicy::DumpFormat) -> Result<()> {
+    sepolicy::dump_module_rules(&active_sepolicy_rules()?, format)
 }
 
 pub fn exec_script<T: AsRef<Path>>(path: T, wait: bool) -> Result<()> {
@@ -370,6 +449,21 @@ fn _install_module(zip: &str) -> Result<()> {
     let entry_path = PathBuf::from_str("module.prop")?;
     let zip_path = PathBuf::from_str(zip)?;
     let zip_path = zip_path.canonicalize()?;
+
+    let report = module_verify::verify_zip(&zip_path)?;
+    report.print(true);
+    if report.errors() > 0 {
+        println!("\n❌ Installation Blocked");
+        println!("┌────────────────────────────────");
+        println!("│ The module failed {} checks", report.errors());
+        println!("│");
+        println!("│ See the full report with");
+        println!("│   apd module verify {zip}");
+        println!("└─────────────────────────────────\n");
+        bail!("Module failed verification");
+    }
+    let signature = module_signature::check_install(&zip_path)?;
+
     zip_extract_file_to_memory(&zip_path, &entry_path, &mut buffer)?;
     let mut module_prop = HashMap::new();
     PropertiesIter::new_with_encoding(Cursor::new(buffer), encoding_rs::UTF_8).read_into(
@@ -445,6 +539,7 @@ fn _install_module(zip: &str) -> Result<()> {
     let module_dir = format!("{}{}", modules_dir.display(), module_id);
     let _module_update_dir = format!("{}{}", modules_update_dir.display(), module_id);
     info!("module dir: {}", module_dir);
+    save_live_state(module_id)?;
     if !Path::new(&module_dir.clone()).exists() {
         fs::create_dir(&module_dir.clone()).expect("Failed to create module folder");
         let permissions = fs::Permissions::from_mode(0o700);
@@ -457,15 +552,18 @@ fn _install_module(zip: &str) -> Result<()> {
 
     println!("- Running module installer");
     exec_install_script(zip, is_metamodule)?;
+    let staged_module_dir = Path::new(&_module_update_dir);
 
-    // set permission and selinux context for $MOD/system
-    let module_system_dir = PathBuf::from(module_dir.clone()).join("system");
-    if module_system_dir.exists() {
-        #[cfg(unix)]
-        fs::set_permissions(&module_system_dir, fs::Permissions::from_mode(0o755))?;
-        restorecon::restore_syscon(&module_system_dir)?;
+    if let Err(e) = check_device_nodes(staged_module_dir)
+        .and_then(|()| module_image::ensure_storable(staged_module_dir))
+    {
+        let _ = remove_dir_all(staged_module_dir);
+        return Err(e);
     }
 
+    label_staged_module(staged_module_dir)?;
+    module_signature::record(staged_module_dir, signature.as_ref())?;
+
     // Create symlink for metamodule
     if is_metamodule {
         println!("- Creating metamodule symlink");
@@ -476,11 +574,140 @@ fn _install_module(zip: &str) -> Result<()> {
     Ok(())
 }
 
+/// Set permission and selinux context for a staged $MOD/system
+pub fn label_staged_module(staged_module_dir: &Path) -> Result<()> {
+    let module_system_dir = staged_module_dir.join("system");
+    if module_system_dir.exists() {
+        #[cfg(unix)]
+        fs::set_permissions(&module_system_dir, fs::Permissions::from_mode(0o755))?;
+    }
+    if let Some(labeler) = ModuleLabeler::new(staged_module_dir) {
+        println!("- Applying module file_contexts");
+        labeler.label(staged_module_dir)?;
+    } else if module_system_dir.exists() {
+        restorecon::restore_syscon(&module_system_dir)?;
+    }
+    Ok(())
+}
+
+/// Refuse device nodes which would hand out raw hardware access once mounted
+///
+/// Whiteouts (0:0 char devices) and char devices only root can open are fine
+fn check_device_nodes(module_dir: &Path) -> Result<()> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let mut unsafe_nodes = vec![];
+    for entry in walkdir::WalkDir::new(module_dir).into_iter().flatten() {
+        let file_type = entry.file_type();
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        let reason = if file_type.is_block_device() {
+            "block device"
+        } else if file_type.is_char_device() && metadata.rdev() != 0 && metadata.mode() & 0o066 != 0
+        {
+            "char device accessible to other users"
+        } else {
+            continue;
+        };
+        unsafe_nodes.push(format!("{}: {reason}", entry.path().display()));
+    }
+    if unsafe_nodes.is_empty() {
+        return Ok(());
+    }
+    println!("\n❌ Installation Blocked");
+    println!("┌────────────────────────────────");
+    println!("│ The module contains unsafe device nodes:");
+    for node in &unsafe_nodes {
+        println!("│   {node}");
+    }
+    println!("└─────────────────────────────────\n");
+    bail!("Module contains {} unsafe device nodes", unsafe_nodes.len());
+}
+
 pub fn install_module(zip: &str) -> Result<()> {
     let result = _install_module(zip);
     result
 }
 
+/// Turn an install source into a zip file: `-` is read from stdin, a directory is packed
+fn prepare_install_source(source: &str, index: usize) -> Result<PathBuf> {
+    let path = Path::new(source);
+    if source != STDIN_SOURCE && !path.is_dir() {
+        return Ok(path.to_path_buf());
+    }
+    ensure_dir_exists(INSTALL_STAGING_DIR)?;
+    let zip_path = Path::new(INSTALL_STAGING_DIR).join(format!("{index}.zip"));
+    if source == STDIN_SOURCE {
+        let mut file = fs::File::create(&zip_path)?;
+        std::io::copy(&mut std::io::stdin().lock(), &mut file)
+            .context("Failed to read the module zip from stdin")?;
+    } else {
+        ensure!(
+            path.join("module.prop").exists(),
+            "{source} has no module.prop"
+        );
+        println!("- Packing {source}");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&zip_path)?);
+        add_zip_tree(&mut zip, path, "", &[])?;
+        zip.finish()?;
+    }
+    Ok(zip_path)
+}
+
+/// Install modules in order, from zips, stdin and module directories
+pub fn install_modules(sources: &[String], continue_on_error: bool) -> Result<()> {
+    ensure_boot_completed()?;
+    ensure!(
+        sources.iter().filter(|s| *s == STDIN_SOURCE).count() <= 1,
+        "stdin can only be read once"
+    );
+    let mut results: Vec<(&str, Result<()>)> = vec![];
+    for (index, source) in sources.iter().enumerate() {
+        let result = prepare_install_source(source, index).and_then(|zip| {
+            let result = install_module(&zip.to_string_lossy());
+            if zip.starts_with(INSTALL_STAGING_DIR) {
+                let _ = fs::remove_file(&zip);
+            }
+            result
+        });
+        let failed = result.is_err();
+        results.push((source, result));
+        if failed && !continue_on_error {
+            break;
+        }
+    }
+    let _ = remove_dir_all(INSTALL_STAGING_DIR);
+
+    // a single module reports its own error, as before
+    if sources.len() == 1
+        && let Some((_, result)) = results.pop()
+    {
+        return result;
+    }
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    println!(
+        "\n- Installed {} of {} modules",
+        results.len() - failed,
+        sources.len()
+    );
+    for (source, result) in &results {
+        match result {
+            Ok(()) => println!("  ✓ {source}"),
+            Err(e) => println!("  ✗ {source}: {e:#}"),
+        }
+    }
+    for source in &sources[results.len()..] {
+        println!("  - {source}: skipped");
+    }
+    ensure!(
+        failed == 0,
+        "{failed} of {} modules failed to install",
+        sources.len()
+    );
+    Ok(())
+}
+
 pub fn _uninstall_module(id: &str, update_dir: &str) -> Result<()> {
     let dir = Path::new(update_dir);
     ensure!(dir.exists(), "No module installed");
@@ -522,6 +749,93 @@ pub fn _uninstall_module(id: &str, update_dir: &str) -> Result<()> {
     let _ = mark_module_state(id, defs::REMOVE_FILE_NAME, true);
     Ok(())
 }
+/// What staging changes in the live module dir, its module.prop and the disable and remove
+/// flags, saved before the first staged update
+///
+/// No saved state means the module didn't exist before it was staged
+pub fn save_live_state(id: &str) -> Result<()> {
+    let saved = Path::new(defs::MODULE_PENDING_STATE_DIR).join(id);
+    if Path::new(MODULE_UPDATE_DIR).join(id).exists() {
+        // already staged, the state from before the first install is the one to keep
+        return Ok(());
+    }
+    if saved.exists() {
+        remove_dir_all(&saved)?;
+    }
+    let live = Path::new(MODULE_DIR).join(id);
+    if !live.join("module.prop").exists() {
+        return Ok(());
+    }
+    ensure_dir_exists(&saved)?;
+    fs::copy(live.join("module.prop"), saved.join("module.prop"))?;
+    for flag in [defs::DISABLE_FILE_NAME, defs::REMOVE_FILE_NAME] {
+        if live.join(flag).exists() {
+            ensure_file_exists(saved.join(flag))?;
+        }
+    }
+    Ok(())
+}
+
+/// Drop the staged copy of a module, putting back what its installer changed in the live dir
+pub fn discard_update(id: &str) -> Result<()> {
+    let staged = Path::new(MODULE_UPDATE_DIR).join(id);
+    ensure!(staged.is_dir(), "module {id} has no staged update");
+    remove_dir_all(&staged)?;
+
+    let live = Path::new(MODULE_DIR).join(id);
+    let saved = Path::new(defs::MODULE_PENDING_STATE_DIR).join(id);
+    if saved.exists() {
+        fs::copy(saved.join("module.prop"), live.join("module.prop"))?;
+        for flag in [defs::DISABLE_FILE_NAME, defs::REMOVE_FILE_NAME] {
+            if saved.join(flag).exists() {
+                ensure_file_exists(live.join(flag))?;
+            } else {
+                let _ = fs::remove_file(live.join(flag));
+            }
+        }
+        let _ = fs::remove_file(live.join(defs::UPDATE_FILE_NAME));
+        remove_dir_all(&saved)?;
+    } else if live.exists() {
+        // a fresh install only leaves the installer's module.prop and update flag until reboot,
+        // anything else means the live dir holds a module whose state wasn't saved
+        let installer_only = fs::read_dir(&live)?.flatten().all(|entry| {
+            let name = entry.file_name();
+            name == "module.prop" || name == defs::UPDATE_FILE_NAME
+        });
+        if installer_only {
+            if metamodule::get_metamodule_path().is_some_and(|path| path == live) {
+                metamodule::remove_symlink()?;
+            }
+            remove_dir_all(&live)?;
+        } else {
+            let _ = fs::remove_file(live.join(defs::UPDATE_FILE_NAME));
+            warn!(
+                "kept {}, it holds more than a fresh install",
+                live.display()
+            );
+        }
+    }
+    refresh_update_marker()?;
+    println!("- Discarded the staged update of {id}");
+    Ok(())
+}
+
+/// Take back an uninstall which hasn't been applied by a reboot yet
+pub fn restore_pending(id: &str) -> Result<()> {
+    let mut restored = false;
+    for dir in [MODULE_DIR, MODULE_UPDATE_DIR] {
+        let remove_file = Path::new(dir).join(id).join(defs::REMOVE_FILE_NAME);
+        if remove_file.exists() {
+            fs::remove_file(&remove_file)?;
+            restored = true;
+        }
+    }
+    ensure!(restored, "module {id} is not pending removal");
+    refresh_update_marker()?;
+    println!("- {id} will be kept");
+    Ok(())
+}
+
 pub fn uninstall_module(id: &str) -> Result<()> {
     _uninstall_module(id, defs::MODULE_DIR)?;
     mark_update()?;
@@ -551,15 +865,15 @@ pub fn read_module_prop(module_path: &Path) -> Result<HashMap<String, String>> {
 }
 
 pub fn save_text<P: AsRef<Path>>(filename: P, content: &str) -> std::io::Result<()> {
-    let _ = ensure_dir_exists("/data/adb/config");
-    let path = Path::new("/data/adb/config").join(filename);
+    let _ = ensure_dir_exists(defs::MODULE_CONFIG_DIR);
+    let path = Path::new(defs::MODULE_CONFIG_DIR).join(filename);
     fs::write(path, content)?;
     Ok(())
 }
 
 pub fn load_text<P: AsRef<Path>>(filename: P) -> std::io::Result<String> {
-    let _ = ensure_dir_exists("/data/adb/config");
-    let path = Path::new("/data/adb/config").join(filename);
+    let _ = ensure_dir_exists(defs::MODULE_CONFIG_DIR);
+    let path = Path::new(defs::MODULE_CONFIG_DIR).join(filename);
     fs::read_to_string(path)
 }
 
@@ -774,7 +1088,7 @@ pub fn disable_all_modules() -> Result<()> {
     Ok(())
 }
 
-fn _list_modules(path: &str) -> Vec<HashMap<String, String>> {
+pub fn _list_modules(path: &str) -> Vec<HashMap<String, String>> {
     // first check enabled modules
     let dir = fs::read_dir(path);
     let Ok(dir) = dir else {
@@ -829,6 +1143,11 @@ fn _list_modules(path: &str) -> Vec<HashMap<String, String>> {
         module_prop_map.insert("remove".to_owned(), remove.to_string());
         module_prop_map.insert("web".to_owned(), web.to_string());
         module_prop_map.insert("action".to_owned(), action.to_string());
+        let (signature, signer) = module_signature::recorded(&path);
+        module_prop_map.insert("signature".to_owned(), signature);
+        if let Some(signer) = signer {
+            module_prop_map.insert("signer".to_owned(), signer);
+        }
 
         if result.is_err() {
             warn!("Failed to parse module.prop: {}", module_prop.display());
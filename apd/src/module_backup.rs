@@ -0,0 +1,318 @@
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::{Read, Write},
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use const_format::concatcp;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use zip::{CompressionMethod, ZipArchive, ZipWriter, write::SimpleFileOptions};
+
+use crate::{
+    defs, metamodule, module, module_signature, module_verify,
+    utils::{add_zip_tree, ensure_dir_exists, ensure_file_exists},
+};
+
+const MANIFEST_NAME: &str = "manifest.json";
+const MANIFEST_VERSION: u32 = 1;
+const MODULES_PREFIX: &str = "modules";
+const CONFIG_PREFIX: &str = "config";
+const RESTORE_DIR: &str = concatcp!(defs::WORKING_DIR, "restore/");
+
+/// Flags which only make sense on the device the backup was taken on
+const TRANSIENT_FLAGS: [&str; 2] = [defs::REMOVE_FILE_NAME, defs::UPDATE_FILE_NAME];
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupManifest {
+    version: u32,
+    created: u64,
+    modules: Vec<BackupEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupEntry {
+    id: String,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    version: String,
+    #[serde(default)]
+    version_code: String,
+    enabled: bool,
+    skip_mount: bool,
+    /// names below the config dir, as `setConfig` saved them
+    config: Vec<String>,
+}
+
+/// Whether a top-level name in the config dir is named after a module: `<id>`, `<id>.*`, `<id>_*`
+pub fn owns_config(id: &str, top: &str) -> bool {
+    top == id
+        || top
+            .strip_prefix(id)
+            .is_some_and(|rest| rest.starts_with('.') || rest.starts_with('_'))
+}
+
+/// The module a top-level name in the config dir belongs to, the longest id it is named after,
+/// so `foo` never claims the config of `foo_bar`
+pub fn config_owner<'a>(top: &str, ids: &'a BTreeSet<String>) -> Option<&'a str> {
+    ids.iter()
+        .filter(|id| owns_config(id, top))
+        .max_by_key(|id| id.len())
+        .map(String::as_str)
+}
+
+/// Every module id installed or staged, whatever its state
+pub fn known_ids() -> BTreeSet<String> {
+    [defs::MODULE_DIR, defs::MODULE_UPDATE_DIR]
+        .into_iter()
+        .filter_map(|dir| fs::read_dir(dir).ok())
+        .flat_map(|entries| entries.flatten())
+        .filter(|entry| entry.path().join("module.prop").exists())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect()
+}
+
+/// Whether a config name stays below the config dir
+pub fn is_config_name(name: &str) -> bool {
+    !name.is_empty()
+        && Path::new(name)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// Config files of a module: `<id>`, `<id>.*`, `<id>_*` and everything in `<id>/`, unless
+/// another known module has the longer matching id
+pub fn config_files(id: &str) -> Vec<String> {
+    let mut ids = known_ids();
+    ids.insert(id.to_string());
+    let config_dir = Path::new(defs::MODULE_CONFIG_DIR);
+    let mut files = vec![];
+    for entry in walkdir::WalkDir::new(config_dir)
+        .min_depth(1)
+        .into_iter()
+        .flatten()
+    {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let Ok(relative) = entry.path().strip_prefix(config_dir) else {
+            continue;
+        };
+        let relative = relative.to_string_lossy().to_string();
+        let top = relative.split('/').next().unwrap_or_default();
+        if config_owner(top, &ids) == Some(id) {
+            files.push(relative);
+        }
+    }
+    files.sort();
+    files
+}
+
+fn installed_modules() -> Result<Vec<String>> {
+    let mut ids = vec![];
+    for entry in fs::read_dir(defs::MODULE_DIR)?.flatten() {
+        if entry.path().join("module.prop").exists() {
+            ids.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+/// Archive modules with their state flags and config files
+pub fn backup_modules(ids: &[String], all: bool, output: &Path) -> Result<()> {
+    let ids = if all {
+        installed_modules()?
+    } else {
+        ids.to_vec()
+    };
+    ensure!(!ids.is_empty(), "no modules to back up");
+
+    let file = fs::File::create(output)
+        .with_context(|| format!("Failed to create {}", output.display()))?;
+    let mut zip = ZipWriter::new(file);
+    let mut manifest = BackupManifest {
+        version: MANIFEST_VERSION,
+        created: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        modules: vec![],
+    };
+
+    for id in &ids {
+        let module_dir = Path::new(defs::MODULE_DIR).join(id);
+        let prop = module::read_module_prop(&module_dir)
+            .with_context(|| format!("module {id} not found"))?;
+        let prop_value = |key: &str| prop.get(key).cloned().unwrap_or_default();
+
+        add_zip_tree(
+            &mut zip,
+            &module_dir,
+            &format!("{MODULES_PREFIX}/{id}"),
+            &TRANSIENT_FLAGS,
+        )?;
+        let config = config_files(id);
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        for name in &config {
+            zip.start_file(format!("{CONFIG_PREFIX}/{id}/{name}"), options)?;
+            let path = Path::new(defs::MODULE_CONFIG_DIR).join(name);
+            std::io::copy(&mut fs::File::open(path)?, &mut zip)?;
+        }
+
+        println!("- Backed up {id} ({} config files)", config.len());
+        manifest.modules.push(BackupEntry {
+            id: id.clone(),
+            name: prop_value("name"),
+            version: prop_value("version"),
+            version_code: prop_value("versionCode"),
+            enabled: !module_dir.join(defs::DISABLE_FILE_NAME).exists(),
+            skip_mount: module_dir.join(defs::SKIP_MOUNT_FILE_NAME).exists(),
+            config,
+        });
+    }
+
+    zip.start_file(MANIFEST_NAME, SimpleFileOptions::default())?;
+    zip.write_all(serde_json::to_string_pretty(&manifest)?.as_bytes())?;
+    zip.finish()?;
+    println!("- Saved {} modules to {}", ids.len(), output.display());
+    Ok(())
+}
+
+fn read_manifest(archive: &mut ZipArchive<fs::File>) -> Result<BackupManifest> {
+    let mut content = String::new();
+    archive
+        .by_name(MANIFEST_NAME)
+        .context("not an apd module backup")?
+        .read_to_string(&mut content)?;
+    let manifest: BackupManifest =
+        serde_json::from_str(&content).context("invalid backup manifest")?;
+    ensure!(
+        manifest.version <= MANIFEST_VERSION,
+        "backup version {} is newer than this apd",
+        manifest.version
+    );
+    Ok(manifest)
+}
+
+/// Stage modules of a backup in modules_update, so they replace the installed ones on next boot
+pub fn restore_modules(archive_path: &Path, only: &[String]) -> Result<()> {
+    module::ensure_boot_completed()?;
+
+    let file = fs::File::open(archive_path)
+        .with_context(|| format!("Failed to open {}", archive_path.display()))?;
+    let mut archive = ZipArchive::new(file)?;
+    let manifest = read_manifest(&mut archive)?;
+    let entries: Vec<&BackupEntry> = manifest
+        .modules
+        .iter()
+        .filter(|entry| only.is_empty() || only.contains(&entry.id))
+        .collect();
+    if entries.is_empty() {
+        bail!("no matching modules in {}", archive_path.display());
+    }
+    check_entries(&entries)?;
+    for entry in &entries {
+        module_signature::check_restore(&entry.id)?;
+    }
+    let report = module_verify::verify_backup(archive_path)?;
+    report.print(true);
+    ensure!(
+        report.errors() == 0,
+        "backup failed {} checks, not restoring it",
+        report.errors()
+    );
+
+    let restore_dir = Path::new(RESTORE_DIR);
+    if restore_dir.exists() {
+        fs::remove_dir_all(restore_dir)?;
+    }
+    archive.extract(restore_dir)?;
+    let result =
+        stage_modules(restore_dir, &entries).and_then(|()| restore_config(restore_dir, &entries));
+    if let Err(e) = fs::remove_dir_all(restore_dir) {
+        warn!("Failed to clean {}: {e}", restore_dir.display());
+    }
+    result?;
+
+    module::mark_update()?;
+    println!("- Restored modules take effect after reboot");
+    Ok(())
+}
+
+/// The manifest names the paths that get replaced, so it is checked before anything is extracted
+fn check_entries(entries: &[&BackupEntry]) -> Result<()> {
+    for entry in entries {
+        let id = &entry.id;
+        ensure!(
+            module_verify::is_valid_id(id),
+            "invalid module id `{id}` in the backup"
+        );
+        if let Some(name) = entry.config.iter().find(|name| !is_config_name(name)) {
+            bail!("config `{name}` of {id} is outside the config dir");
+        }
+    }
+    Ok(())
+}
+
+fn stage_modules(restore_dir: &Path, entries: &[&BackupEntry]) -> Result<()> {
+    let update_dir = Path::new(defs::MODULE_UPDATE_DIR);
+    ensure_dir_exists(update_dir)?;
+    for entry in entries {
+        let id = &entry.id;
+        let source = restore_dir.join(MODULES_PREFIX).join(id);
+        ensure!(
+            source.join("module.prop").exists(),
+            "module {id} is missing from the backup"
+        );
+
+        module::save_live_state(id)?;
+        let staged: PathBuf = update_dir.join(id);
+        if staged.exists() {
+            fs::remove_dir_all(&staged)?;
+        }
+        fs::rename(&source, &staged)?;
+        module::label_staged_module(&staged)?;
+
+        // the flags of the live dir are carried over on boot, so they have to match the backup
+        let live = Path::new(defs::MODULE_DIR).join(id);
+        if live.exists() {
+            let _ = fs::remove_file(live.join(defs::REMOVE_FILE_NAME));
+            let disable = live.join(defs::DISABLE_FILE_NAME);
+            if entry.enabled {
+                let _ = fs::remove_file(disable);
+            } else {
+                ensure_file_exists(disable)?;
+            }
+        }
+        if module::read_module_prop(&staged).is_ok_and(|props| metamodule::is_metamodule(&props)) {
+            metamodule::ensure_symlink(&live)?;
+        }
+
+        info!("staged {id} from backup");
+        println!("- Restored {id} {}", entry.version);
+    }
+    Ok(())
+}
+
+/// Copy the config of the restored modules, once all of them are staged
+fn restore_config(restore_dir: &Path, entries: &[&BackupEntry]) -> Result<()> {
+    for entry in entries {
+        let config_source = restore_dir.join(CONFIG_PREFIX).join(&entry.id);
+        for name in &entry.config {
+            let target = Path::new(defs::MODULE_CONFIG_DIR).join(name);
+            if let Some(parent) = target.parent() {
+                ensure_dir_exists(parent)?;
+            }
+            fs::copy(config_source.join(name), &target)
+                .with_context(|| format!("Failed to restore config {name}"))?;
+        }
+        println!(
+            "- Restored {} config files of {}",
+            entry.config.len(),
+            entry.id
+        );
+    }
+    Ok(())
+}
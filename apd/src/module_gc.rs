@@ -0,0 +1,225 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Result, ensure};
+use log::{info, warn};
+
+use crate::{defs, metamodule, module, module_backup, utils::dir_size};
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    /// a module dir without module.prop, which no listing shows
+    BrokenModule,
+    /// a modules_update entry which can't be applied on boot, left there before the last boot
+    BrokenUpdate,
+    /// the metamodule symlink pointing to no metamodule
+    StaleMetamodule,
+    /// config files named after no known module, only reported: `setConfig` takes any name,
+    /// so they may still belong to an installed module
+    OrphanedConfig,
+}
+
+impl Kind {
+    fn removable(self) -> bool {
+        !matches!(self, Kind::OrphanedConfig)
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Kind::BrokenModule => write!(f, "module without module.prop"),
+            Kind::BrokenUpdate => write!(f, "broken staged update"),
+            Kind::StaleMetamodule => write!(f, "stale metamodule symlink"),
+            Kind::OrphanedConfig => write!(f, "orphaned config"),
+        }
+    }
+}
+
+struct Garbage {
+    kind: Kind,
+    path: PathBuf,
+    size: u64,
+}
+
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{size} {}", UNITS[0])
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+/// Entries of a dir, symlinks left out, so nothing outside it is ever looked at
+fn entries(dir: &str) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return vec![];
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|t| !t.is_symlink()))
+        .map(|entry| entry.path())
+        .collect();
+    paths.sort();
+    paths
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+fn size_of(path: &Path) -> u64 {
+    if path.is_dir() {
+        dir_size(path)
+    } else {
+        path.symlink_metadata().map(|m| m.len()).unwrap_or(0)
+    }
+}
+
+/// Whether an entry is older than the boot state, which is saved on every boot
+fn staged_before_boot(path: &Path) -> bool {
+    let Ok(booted) = fs::metadata(defs::BOOT_STATE_FILE).and_then(|m| m.modified()) else {
+        return false;
+    };
+    path.symlink_metadata()
+        .and_then(|m| m.modified())
+        .is_ok_and(|modified| modified < booted)
+}
+
+fn find_garbage() -> Vec<Garbage> {
+    let known = module_backup::known_ids();
+    let mut garbage = vec![];
+    let mut push = |kind, path: PathBuf| {
+        let size = size_of(&path);
+        garbage.push(Garbage { kind, path, size });
+    };
+
+    for path in entries(defs::MODULE_DIR) {
+        // the root of a mounted image holds its own entries, like lost+found
+        let name = file_name(&path);
+        if name.starts_with('.') || name == "lost+found" {
+            continue;
+        }
+        // an install in progress creates the dir before the installer fills it
+        let staged = Path::new(defs::MODULE_UPDATE_DIR).join(&name);
+        if path.is_dir() && !path.join("module.prop").exists() && !staged.exists() {
+            push(Kind::BrokenModule, path);
+        }
+    }
+
+    // an install may still be writing what was staged since the last boot
+    for path in entries(defs::MODULE_UPDATE_DIR) {
+        if staged_before_boot(&path) && (!path.is_dir() || !path.join("module.prop").exists()) {
+            push(Kind::BrokenUpdate, path);
+        }
+    }
+
+    let symlink = Path::new(defs::METAMODULE_DIR.trim_end_matches('/'));
+    if symlink.is_symlink() {
+        let is_metamodule = fs::canonicalize(symlink)
+            .ok()
+            .and_then(|target| module::read_module_prop(&target).ok())
+            .is_some_and(|prop| metamodule::is_metamodule(&prop));
+        if !is_metamodule {
+            push(Kind::StaleMetamodule, symlink.to_path_buf());
+        }
+    }
+
+    for path in entries(defs::MODULE_CONFIG_DIR) {
+        let name = file_name(&path);
+        if module_backup::config_owner(&name, &known).is_none() {
+            push(Kind::OrphanedConfig, path);
+        }
+    }
+    garbage
+}
+
+fn remove(garbage: &Garbage) -> Result<()> {
+    match garbage.kind {
+        Kind::StaleMetamodule => metamodule::remove_symlink(),
+        _ if garbage.path.is_dir() => Ok(fs::remove_dir_all(&garbage.path)?),
+        _ => Ok(fs::remove_file(&garbage.path)?),
+    }
+}
+
+/// Remove what uninstalls and failed installs left behind in /data/adb, config files which
+/// seem orphaned are only listed
+pub fn gc(dry_run: bool) -> Result<()> {
+    let garbage = find_garbage();
+    if garbage.is_empty() {
+        println!("- Nothing to clean");
+        return Ok(());
+    }
+
+    let (garbage, kept): (Vec<Garbage>, Vec<Garbage>) =
+        garbage.into_iter().partition(|item| item.kind.removable());
+    for item in &kept {
+        println!(
+            "{}\t{}\t{}",
+            format_size(item.size),
+            item.kind,
+            item.path.display()
+        );
+    }
+    if !kept.is_empty() {
+        println!(
+            "- Kept {} items, remove them by hand if no module uses them",
+            kept.len()
+        );
+    }
+    if garbage.is_empty() {
+        return Ok(());
+    }
+
+    let mut freed = 0;
+    let mut failed = 0;
+    for item in &garbage {
+        println!(
+            "{}\t{}\t{}",
+            format_size(item.size),
+            item.kind,
+            item.path.display()
+        );
+        if dry_run {
+            continue;
+        }
+        match remove(item) {
+            Ok(()) => {
+                info!("gc: removed {}", item.path.display());
+                freed += item.size;
+            }
+            Err(e) => {
+                warn!("Failed to remove {}: {e}", item.path.display());
+                failed += 1;
+            }
+        }
+    }
+
+    let total: u64 = garbage.iter().map(|item| item.size).sum();
+    if dry_run {
+        println!(
+            "- {} items, {} would be freed",
+            garbage.len(),
+            format_size(total)
+        );
+    } else {
+        println!(
+            "- Removed {} items, freed {}",
+            garbage.len() - failed,
+            format_size(freed)
+        );
+        ensure!(failed == 0, "{failed} items could not be removed");
+    }
+    Ok(())
+}
@@ -0,0 +1,165 @@
+use std::{
+    cmp::Reverse,
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, bail};
+use log::{info, warn};
+
+use crate::{
+    defs, module, module_image, module_signature,
+    utils::{copy_dir_all, ensure_dir_exists, move_dir},
+};
+
+/// How many replaced versions of a module are kept
+const HISTORY_KEEP: usize = 3;
+
+/// A replaced version of a module, kept as `<timestamp>_<versionCode>`
+pub struct Snapshot {
+    pub path: PathBuf,
+    pub timestamp: u64,
+    pub version_code: String,
+}
+
+fn history_dir(id: &str) -> PathBuf {
+    Path::new(defs::MODULE_HISTORY_DIR).join(id)
+}
+
+/// Snapshots of a module, newest first
+pub fn snapshots(id: &str) -> Result<Vec<Snapshot>> {
+    let dir = history_dir(id);
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut snapshots = vec![];
+    for entry in fs::read_dir(&dir)?.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Some((timestamp, version_code)) = name.split_once('_') else {
+            continue;
+        };
+        let Ok(timestamp) = timestamp.parse() else {
+            continue;
+        };
+        snapshots.push(Snapshot {
+            path: entry.path(),
+            timestamp,
+            version_code: version_code.to_string(),
+        });
+    }
+    snapshots.sort_by_key(|snapshot| Reverse(snapshot.timestamp));
+    Ok(snapshots)
+}
+
+fn prune(id: &str) -> Result<()> {
+    for snapshot in snapshots(id)?.iter().skip(HISTORY_KEEP) {
+        info!("drop old version {}", snapshot.path.display());
+        fs::remove_dir_all(&snapshot.path)?;
+    }
+    Ok(())
+}
+
+/// Move a module which is about to be replaced into its history
+pub fn snapshot_module(module_dir: &Path) -> Result<()> {
+    let id = module_dir
+        .file_name()
+        .context("invalid module dir")?
+        .to_string_lossy()
+        .to_string();
+    let version_code: String = module::read_module_prop(module_dir)
+        .ok()
+        .and_then(|prop| prop.get("versionCode").cloned())
+        .unwrap_or_default()
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect();
+    let version_code = if version_code.is_empty() {
+        "0".to_string()
+    } else {
+        version_code
+    };
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    let dir = history_dir(&id);
+    ensure_dir_exists(&dir)?;
+    let target = dir.join(format!("{timestamp}_{version_code}"));
+    if target.exists() {
+        fs::remove_dir_all(&target)?;
+    }
+    move_dir(module_dir, &target)?;
+    info!("kept {id} {version_code} in {}", target.display());
+    if let Err(e) = prune(&id) {
+        warn!("Failed to prune the history of {id}: {e}");
+    }
+    Ok(())
+}
+
+pub fn list_history(id: &str) -> Result<()> {
+    let snapshots = snapshots(id)?;
+    if snapshots.is_empty() {
+        println!("- No older versions of {id}");
+        return Ok(());
+    }
+    for snapshot in snapshots {
+        let version = module::read_module_prop(&snapshot.path)
+            .ok()
+            .and_then(|prop| prop.get("version").cloned())
+            .unwrap_or_default();
+        println!(
+            "{}\t{}\t{}",
+            snapshot.version_code, version, snapshot.timestamp
+        );
+    }
+    Ok(())
+}
+
+/// Stage an older version of a module, so it replaces the installed one on next boot
+pub fn rollback(id: &str, to: Option<&str>) -> Result<()> {
+    module::ensure_boot_completed()?;
+
+    let snapshots = snapshots(id)?;
+    let snapshot = match to {
+        Some(version_code) => snapshots
+            .iter()
+            .find(|snapshot| snapshot.version_code == version_code),
+        None => snapshots.first(),
+    };
+    let Some(snapshot) = snapshot else {
+        match to {
+            Some(version_code) => bail!("no version {version_code} of {id} is kept"),
+            None => bail!("no older version of {id} is kept"),
+        }
+    };
+
+    module_signature::check_rollback(&snapshot.path)?;
+    module_image::ensure_storable(&snapshot.path)?;
+
+    module::save_live_state(id)?;
+    let update_dir = Path::new(defs::MODULE_UPDATE_DIR);
+    ensure_dir_exists(update_dir)?;
+    let staged = update_dir.join(id);
+    if staged.exists() {
+        fs::remove_dir_all(&staged)?;
+    }
+    // copied rather than moved, so the snapshot stays in the history; the copy keeps device
+    // nodes, whiteouts and xattrs the way move_dir would
+    copy_dir_all(&snapshot.path, &staged)?;
+    for flag in [defs::REMOVE_FILE_NAME, defs::UPDATE_FILE_NAME] {
+        let _ = fs::remove_file(staged.join(flag));
+    }
+    module::label_staged_module(&staged)?;
+
+    // a pending removal would drop the rolled back module as well
+    let _ = fs::remove_file(
+        Path::new(defs::MODULE_DIR)
+            .join(id)
+            .join(defs::REMOVE_FILE_NAME),
+    );
+    module::mark_update()?;
+    println!(
+        "- Rolled {id} back to {}, takes effect after reboot",
+        snapshot.version_code
+    );
+    Ok(())
+}
@@ -0,0 +1,579 @@
+use std::{
+    ffi::CString,
+    fs,
+    os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use log::{error, info, warn};
+use loopdev::{LoopControl, LoopDevice};
+use procfs::process::Process;
+use rustix::mount::{MountFlags, UnmountFlags, mount, unmount};
+
+use crate::{
+    defs,
+    restorecon::{ADB_CON, lsetfilecon},
+    utils,
+};
+
+const MIB: u64 = 1024 * 1024;
+const DEFAULT_IMAGE_SIZE: u64 = 256 * MIB;
+// free space kept in an ext4 image, it is grown in steps of this size
+const MIN_FREE_SPACE: u64 = 64 * MIB;
+
+const OVERLAY_SOURCE: &str = "apd_modules";
+
+// e2fsck exit code bit for errors left uncorrected
+const FSCK_ERRORS_LEFT: i32 = 4;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFs {
+    /// a writable image which is grown as modules need room
+    Ext4,
+    /// a compressed read-only image, changes are kept in an overlay and folded in at boot
+    Erofs,
+}
+
+impl ImageFs {
+    fn name(self) -> &'static str {
+        match self {
+            ImageFs::Ext4 => "ext4",
+            ImageFs::Erofs => "erofs",
+        }
+    }
+
+    fn image(self) -> &'static Path {
+        Path::new(match self {
+            ImageFs::Ext4 => defs::MODULE_IMAGE_FILE,
+            ImageFs::Erofs => defs::MODULE_EROFS_FILE,
+        })
+    }
+}
+
+/// Whiteouts (0:0 char devices) in a tree, below the erofs overlay overlayfs takes them for its own
+fn whiteouts(dir: &Path) -> Vec<PathBuf> {
+    walkdir::WalkDir::new(dir)
+        .into_iter()
+        .flatten()
+        .filter(|entry| {
+            entry.file_type().is_char_device() && entry.metadata().is_ok_and(|m| m.rdev() == 0)
+        })
+        .map(walkdir::DirEntry::into_path)
+        .collect()
+}
+
+fn ensure_no_whiteouts(dirs: &[&Path]) -> Result<()> {
+    let found: Vec<String> = dirs
+        .iter()
+        .flat_map(|dir| whiteouts(dir))
+        .map(|path| path.display().to_string())
+        .collect();
+    ensure!(
+        found.is_empty(),
+        "erofs module storage would drop the whiteouts {}",
+        found.join(", ")
+    );
+    Ok(())
+}
+
+/// Refuse a module tree the configured storage can not keep as it is
+pub fn ensure_storable(module_dir: &Path) -> Result<()> {
+    if configured_fs() != Some(ImageFs::Erofs) {
+        return Ok(());
+    }
+    ensure_no_whiteouts(&[module_dir])
+}
+
+fn work_dir(name: &str) -> PathBuf {
+    Path::new(defs::MODULE_IMAGE_WORK_DIR).join(name)
+}
+
+/// The image type modules are configured to live in, None for a plain directory
+pub fn configured_fs() -> Option<ImageFs> {
+    let content = fs::read_to_string(defs::MODULE_STORAGE_FILE).ok()?;
+    match content.trim() {
+        "ext4" => Some(ImageFs::Ext4),
+        "erofs" => Some(ImageFs::Erofs),
+        other => {
+            warn!("unknown module storage {other}, using a plain directory");
+            None
+        }
+    }
+}
+
+/// Prefer the tools shipped with apd, then the ones of the system
+fn find_tool(name: &str) -> Result<PathBuf> {
+    let bundled = Path::new(defs::BINARY_DIR).join(name);
+    if bundled.exists() {
+        return Ok(bundled);
+    }
+    which::which(name).with_context(|| format!("{name} not found"))
+}
+
+fn run_tool(name: &str, args: &[&str]) -> Result<i32> {
+    let tool = find_tool(name)?;
+    info!("{} {}", tool.display(), args.join(" "));
+    let status = Command::new(&tool)
+        .args(args)
+        .status()
+        .with_context(|| format!("Failed to run {name}"))?;
+    Ok(status.code().unwrap_or(-1))
+}
+
+fn mount_source(target: &Path) -> Option<(String, PathBuf)> {
+    Process::myself()
+        .ok()?
+        .mountinfo()
+        .ok()?
+        .into_iter()
+        .rev()
+        .find(|mount| mount.mount_point == target)
+        .map(|mount| {
+            let source = mount.mount_source.unwrap_or_default();
+            (mount.fs_type, PathBuf::from(source))
+        })
+}
+
+fn attach(image: &Path, read_only: bool) -> Result<PathBuf> {
+    let device = LoopControl::open()?.next_free()?;
+    device
+        .with()
+        .read_only(read_only)
+        .autoclear(true)
+        .attach(image)
+        .with_context(|| format!("Failed to attach {} to a loop device", image.display()))?;
+    device.path().context("loop device has no path")
+}
+
+fn mount_image(fs_type: ImageFs, image: &Path, target: &Path, read_only: bool) -> Result<()> {
+    utils::ensure_dir_exists(target)?;
+    let device = attach(image, read_only)?;
+    let flags = if read_only {
+        MountFlags::RDONLY
+    } else {
+        MountFlags::NOATIME
+    };
+    mount(&device, target, fs_type.name(), flags, c"").with_context(|| {
+        format!(
+            "Failed to mount {} on {}",
+            image.display(),
+            target.display()
+        )
+    })?;
+    Ok(())
+}
+
+fn umount(target: &Path) -> Result<()> {
+    unmount(target, UnmountFlags::DETACH)
+        .with_context(|| format!("Failed to unmount {}", target.display()))?;
+    Ok(())
+}
+
+fn create_ext4(image: &Path, size: u64) -> Result<()> {
+    info!("create {} of {} MiB", image.display(), size / MIB);
+    let file = fs::File::create(image)?;
+    file.set_len(size)?;
+    let image = image.to_string_lossy();
+    let code = run_tool(
+        "mke2fs",
+        &["-t", "ext4", "-q", "-m", "0", "-L", "modules", &image],
+    )?;
+    ensure!(code == 0, "mke2fs failed with {code}");
+    Ok(())
+}
+
+fn fsck_ext4(image: &Path) -> Result<()> {
+    let image = image.to_string_lossy();
+    let code = run_tool("e2fsck", &["-p", "-f", &image])?;
+    if code & FSCK_ERRORS_LEFT == 0 {
+        return Ok(());
+    }
+    warn!("e2fsck could not repair {image} automatically, retrying");
+    let code = run_tool("e2fsck", &["-y", "-f", &image])?;
+    ensure!(code & FSCK_ERRORS_LEFT == 0, "{image} is corrupted");
+    Ok(())
+}
+
+/// Shrinking needs the image offline, so `resize` leaves it to the next boot
+fn apply_pending_resize(image: &Path) -> Result<()> {
+    let Ok(content) = fs::read_to_string(defs::MODULE_IMAGE_RESIZE_FILE) else {
+        return Ok(());
+    };
+    fs::remove_file(defs::MODULE_IMAGE_RESIZE_FILE)?;
+    let size: u64 = content
+        .trim()
+        .parse()
+        .context("invalid pending image size")?;
+    let image_str = image.to_string_lossy();
+    let size_arg = format!("{}K", size / 1024);
+    let code = run_tool("resize2fs", &[&image_str, &size_arg])?;
+    ensure!(code == 0, "resize2fs failed with {code}");
+    fs::OpenOptions::new()
+        .write(true)
+        .open(image)?
+        .set_len(size)?;
+    info!("resized {image_str} to {} MiB", size / MIB);
+    Ok(())
+}
+
+fn grow_ext4(size: u64) -> Result<()> {
+    let Some((fs_type, device)) = mount_source(Path::new(defs::MODULE_DIR)) else {
+        bail!("module image is not mounted");
+    };
+    ensure!(fs_type == "ext4", "modules are not on an ext4 image");
+    fs::OpenOptions::new()
+        .write(true)
+        .open(defs::MODULE_IMAGE_FILE)?
+        .set_len(size)?;
+    LoopDevice::open(&device)?.set_capacity()?;
+    let code = run_tool("resize2fs", &[&device.to_string_lossy()])?;
+    ensure!(code == 0, "resize2fs failed with {code}");
+    info!("grew module image to {} MiB", size / MIB);
+    Ok(())
+}
+
+fn image_size(image: &Path) -> u64 {
+    fs::metadata(image).map_or(0, |metadata| metadata.len())
+}
+
+/// Make sure the ext4 image has room for `bytes` more, growing it online if needed
+pub fn reserve(bytes: u64) -> Result<()> {
+    if configured_fs() != Some(ImageFs::Ext4) {
+        return Ok(());
+    }
+    let stat = rustix::fs::statvfs(defs::MODULE_DIR)?;
+    let free = stat.f_bavail * stat.f_frsize;
+    let needed = bytes + MIN_FREE_SPACE;
+    if free >= needed {
+        return Ok(());
+    }
+    let grow = (needed - free).div_ceil(MIN_FREE_SPACE) * MIN_FREE_SPACE;
+    grow_ext4(image_size(Path::new(defs::MODULE_IMAGE_FILE)) + grow)
+}
+
+fn build_erofs(src: &Path, image: &Path) -> Result<()> {
+    let new_image = image.with_extension("erofs.new");
+    let code = run_tool(
+        "mkfs.erofs",
+        &[&new_image.to_string_lossy(), &src.to_string_lossy()],
+    )?;
+    ensure!(code == 0, "mkfs.erofs failed with {code}");
+    fs::rename(&new_image, image)?;
+    Ok(())
+}
+
+fn mount_erofs_layers(target: &Path) -> Result<()> {
+    let (lower, upper, work) = (work_dir("lower"), work_dir("upper"), work_dir("work"));
+    utils::ensure_dir_exists(&upper)?;
+    utils::ensure_dir_exists(&work)?;
+    mount_image(ImageFs::Erofs, ImageFs::Erofs.image(), &lower, true)?;
+    let data = format!(
+        "lowerdir={},upperdir={},workdir={}",
+        lower.display(),
+        upper.display(),
+        work.display()
+    );
+    let data = CString::new(data)?;
+    if let Err(e) = mount(
+        OVERLAY_SOURCE,
+        target,
+        "overlay",
+        MountFlags::NOATIME,
+        data.as_c_str(),
+    ) {
+        let _ = umount(&lower);
+        return Err(e).with_context(|| format!("Failed to mount overlay on {}", target.display()));
+    }
+    Ok(())
+}
+
+fn unmount_erofs_layers(target: &Path) -> Result<()> {
+    umount(target)?;
+    umount(&work_dir("lower"))
+}
+
+/// Fold the changes kept in the overlay back into the erofs image
+fn compact_erofs() -> Result<()> {
+    let upper = work_dir("upper");
+    let has_changes = fs::read_dir(&upper).is_ok_and(|mut entries| entries.next().is_some());
+    if !has_changes {
+        return Ok(());
+    }
+    find_tool("mkfs.erofs")?;
+    let merged = work_dir("merged");
+    utils::ensure_dir_exists(&merged)?;
+    mount_erofs_layers(&merged)?;
+    let result = build_erofs(&merged, ImageFs::Erofs.image());
+    unmount_erofs_layers(&merged)?;
+    result?;
+    fs::remove_dir_all(&upper)?;
+    fs::remove_dir_all(work_dir("work"))?;
+    info!("folded module changes into the erofs image");
+    Ok(())
+}
+
+fn clear_dir(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)?.flatten() {
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(&path)?;
+        } else {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+fn mount_rw(fs_type: ImageFs, target: &Path) -> Result<()> {
+    match fs_type {
+        ImageFs::Ext4 => mount_image(fs_type, fs_type.image(), target, false),
+        ImageFs::Erofs => mount_erofs_layers(target),
+    }
+}
+
+fn unmount_rw(fs_type: ImageFs, target: &Path) -> Result<()> {
+    match fs_type {
+        ImageFs::Ext4 => umount(target),
+        ImageFs::Erofs => unmount_erofs_layers(target),
+    }
+}
+
+/// Move the modules of the plain directory into a new image
+fn migrate_into(fs_type: ImageFs) -> Result<()> {
+    let modules_dir = Path::new(defs::MODULE_DIR);
+    utils::ensure_dir_exists(defs::MODULE_IMAGE_WORK_DIR)?;
+    info!("moving modules into a new {} image", fs_type.name());
+    match fs_type {
+        ImageFs::Ext4 => {
+            let size = fs::read_to_string(defs::MODULE_IMAGE_RESIZE_FILE)
+                .ok()
+                .and_then(|size| size.trim().parse().ok())
+                .unwrap_or(DEFAULT_IMAGE_SIZE)
+                .max(utils::dir_size(modules_dir) * 3 / 2 + MIN_FREE_SPACE);
+            let _ = fs::remove_file(defs::MODULE_IMAGE_RESIZE_FILE);
+            create_ext4(fs_type.image(), size)?;
+            let staging = work_dir("merged");
+            mount_image(fs_type, fs_type.image(), &staging, false)?;
+            let result = utils::copy_dir_all(modules_dir, &staging);
+            umount(&staging)?;
+            if let Err(e) = result {
+                let _ = fs::remove_file(fs_type.image());
+                return Err(e);
+            }
+        }
+        ImageFs::Erofs => {
+            ensure_no_whiteouts(&[modules_dir])?;
+            build_erofs(modules_dir, fs_type.image())?;
+        }
+    }
+    clear_dir(modules_dir)
+}
+
+/// Move the modules of an image no longer configured back to the plain directory
+fn migrate_out(fs_type: ImageFs) -> Result<()> {
+    let merged = work_dir("merged");
+    info!("moving modules out of the {} image", fs_type.name());
+    mount_rw(fs_type, &merged)?;
+    let result = utils::copy_dir_all(&merged, defs::MODULE_DIR);
+    unmount_rw(fs_type, &merged)?;
+    result?;
+    fs::remove_file(fs_type.image())?;
+    if fs_type == ImageFs::Erofs {
+        fs::remove_dir_all(defs::MODULE_IMAGE_WORK_DIR)?;
+    }
+    Ok(())
+}
+
+/// Set a broken ext4 image aside and start over, salvaging whatever still mounts
+fn recover_ext4() -> Result<()> {
+    let image = ImageFs::Ext4.image();
+    let broken = image.with_extension("img.bad");
+    error!("module image is broken, moving it to {}", broken.display());
+    fs::rename(image, &broken)?;
+    create_ext4(image, image_size(&broken).max(DEFAULT_IMAGE_SIZE))?;
+
+    let (salvage, staging) = (work_dir("salvage"), work_dir("merged"));
+    mount_image(ImageFs::Ext4, &broken, &salvage, true)?;
+    mount_image(ImageFs::Ext4, image, &staging, false)?;
+    let result = utils::copy_dir_all(&salvage, &staging);
+    umount(&staging)?;
+    umount(&salvage)?;
+    result.context("Failed to salvage modules from the broken image")
+}
+
+fn mount_ext4_modules() -> Result<()> {
+    let image = ImageFs::Ext4.image();
+    if let Err(e) = apply_pending_resize(image) {
+        warn!("resize module image failed: {e:#}");
+    }
+    let checked = fsck_ext4(image);
+    if let Err(e) = &checked {
+        warn!("{e:#}");
+    }
+    let modules_dir = Path::new(defs::MODULE_DIR);
+    if checked.is_err() || mount_image(ImageFs::Ext4, image, modules_dir, false).is_err() {
+        if let Err(e) = recover_ext4() {
+            error!("{e:#}");
+        }
+        mount_image(ImageFs::Ext4, image, modules_dir, false)?;
+    }
+    Ok(())
+}
+
+/// Mount the module image over the modules directory, migrating between storage modes first
+pub fn mount_modules_image() -> Result<()> {
+    let modules_dir = Path::new(defs::MODULE_DIR);
+    utils::ensure_dir_exists(modules_dir)?;
+    let configured = configured_fs();
+    for fs_type in [ImageFs::Ext4, ImageFs::Erofs] {
+        if configured != Some(fs_type) && fs_type.image().exists() {
+            migrate_out(fs_type)?;
+        }
+    }
+    let Some(fs_type) = configured else {
+        return Ok(());
+    };
+    if mount_source(modules_dir).is_some() {
+        return Ok(());
+    }
+    if !fs_type.image().exists() {
+        migrate_into(fs_type)?;
+    }
+    match fs_type {
+        ImageFs::Ext4 => mount_ext4_modules()?,
+        ImageFs::Erofs => {
+            if let Err(e) = compact_erofs() {
+                warn!("fold module changes into the image failed: {e:#}");
+            }
+            mount_erofs_layers(modules_dir)?;
+        }
+    }
+    fs::set_permissions(modules_dir, fs::Permissions::from_mode(0o700))?;
+    lsetfilecon(modules_dir, ADB_CON)?;
+    info!("modules mounted from the {} image", fs_type.name());
+    Ok(())
+}
+
+/// Module commands must not write below a configured image which is not mounted
+pub fn ensure_mounted() -> Result<()> {
+    let Some(fs_type) = configured_fs() else {
+        return Ok(());
+    };
+    if !fs_type.image().exists() || mount_source(Path::new(defs::MODULE_DIR)).is_some() {
+        return Ok(());
+    }
+    mount_rw(fs_type, Path::new(defs::MODULE_DIR))
+}
+
+pub fn enable(fs_type: ImageFs, size: Option<u64>) -> Result<()> {
+    match fs_type {
+        ImageFs::Ext4 => {
+            for tool in ["mke2fs", "e2fsck", "resize2fs"] {
+                find_tool(tool)?;
+            }
+        }
+        ImageFs::Erofs => {
+            find_tool("mkfs.erofs")?;
+            ensure_no_whiteouts(&[
+                Path::new(defs::MODULE_DIR),
+                Path::new(defs::MODULE_UPDATE_DIR),
+            ])?;
+        }
+    }
+    if let Some(size) = size {
+        ensure!(fs_type == ImageFs::Ext4, "only ext4 images have a size");
+        fs::write(defs::MODULE_IMAGE_RESIZE_FILE, (size * MIB).to_string())?;
+    }
+    fs::write(defs::MODULE_STORAGE_FILE, fs_type.name())?;
+    println!(
+        "Modules will be moved into an {} image on the next boot",
+        fs_type.name()
+    );
+    Ok(())
+}
+
+pub fn disable() -> Result<()> {
+    if configured_fs().is_none() {
+        println!("Modules are already kept in a plain directory");
+        return Ok(());
+    }
+    fs::remove_file(defs::MODULE_STORAGE_FILE)?;
+    let _ = fs::remove_file(defs::MODULE_IMAGE_RESIZE_FILE);
+    println!("Modules will be moved back to a plain directory on the next boot");
+    Ok(())
+}
+
+pub fn resize(size_mib: u64) -> Result<()> {
+    ensure!(
+        configured_fs() == Some(ImageFs::Ext4),
+        "only ext4 module images can be resized"
+    );
+    let size = size_mib * MIB;
+    let used = utils::dir_size(Path::new(defs::MODULE_DIR));
+    ensure!(
+        size >= used + MIN_FREE_SPACE,
+        "{size_mib} MiB is too small, modules use {} MiB",
+        used / MIB
+    );
+    if size >= image_size(ImageFs::Ext4.image()) {
+        return grow_ext4(size);
+    }
+    fs::write(defs::MODULE_IMAGE_RESIZE_FILE, size.to_string())?;
+    println!("The module image will be shrunk to {size_mib} MiB on the next boot");
+    Ok(())
+}
+
+pub fn fsck() -> Result<()> {
+    let Some(fs_type) = configured_fs() else {
+        bail!("modules are not kept in an image");
+    };
+    let code = match (fs_type, mount_source(Path::new(defs::MODULE_DIR))) {
+        // a mounted image is only checked, repairs happen at boot
+        (ImageFs::Ext4, Some((_, device))) => {
+            run_tool("e2fsck", &["-n", "-f", &device.to_string_lossy()])?
+        }
+        (ImageFs::Ext4, None) => {
+            run_tool("e2fsck", &["-y", "-f", &fs_type.image().to_string_lossy()])?
+        }
+        (ImageFs::Erofs, _) => run_tool("fsck.erofs", &[&fs_type.image().to_string_lossy()])?,
+    };
+    ensure!(
+        code & FSCK_ERRORS_LEFT == 0,
+        "module image has errors ({code})"
+    );
+    println!("Module image is clean");
+    Ok(())
+}
+
+pub fn status() -> Result<()> {
+    let Some(fs_type) = configured_fs() else {
+        println!("storage: directory");
+        return Ok(());
+    };
+    let image = fs_type.image();
+    println!("storage: {}", fs_type.name());
+    println!(
+        "image: {} ({} MiB)",
+        image.display(),
+        image_size(image) / MIB
+    );
+    match mount_source(Path::new(defs::MODULE_DIR)) {
+        Some((mount_fs, source)) => {
+            println!("mounted: {} ({mount_fs})", source.display());
+            let stat = rustix::fs::statvfs(defs::MODULE_DIR)?;
+            let (total, free) = (stat.f_blocks * stat.f_frsize, stat.f_bavail * stat.f_frsize);
+            println!(
+                "used: {} MiB, free: {} MiB",
+                (total - free) / MIB,
+                free / MIB
+            );
+        }
+        None => println!("mounted: no"),
+    }
+    if let Ok(size) = fs::read_to_string(defs::MODULE_IMAGE_RESIZE_FILE) {
+        println!("pending size: {} bytes", size.trim());
+    }
+    Ok(())
+}
@@ -0,0 +1,366 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+    io::{Cursor, Read},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use java_properties::PropertiesIter;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use zip::ZipArchive;
+
+use crate::{
+    defs, module, module_backup,
+    utils::{add_zip_tree, ensure_dir_exists},
+};
+
+/// Module files which describe its state on this device rather than the module
+const STATE_FILES: [&str; 4] = [
+    defs::DISABLE_FILE_NAME,
+    defs::REMOVE_FILE_NAME,
+    defs::UPDATE_FILE_NAME,
+    defs::SIGNATURE_STATUS_FILE_NAME,
+];
+
+/// The module state a device should converge to, paths are relative to the manifest
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    /// uninstall the installed modules the manifest doesn't list
+    #[serde(default)]
+    prune: bool,
+    modules: Vec<ManifestModule>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ManifestModule {
+    id: String,
+    /// zip to install from when the module is missing or has another version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version_code: Option<String>,
+    #[serde(default = "enabled_by_default")]
+    enabled: bool,
+    /// config dir names and the files they should hold
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    config: BTreeMap<String, PathBuf>,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+#[derive(Debug)]
+enum Action {
+    Uninstall(String),
+    Install(String, PathBuf),
+    Upgrade {
+        id: String,
+        from: String,
+        to: String,
+        source: PathBuf,
+    },
+    KeepPendingRemoval(String),
+    Enable(String),
+    Disable(String),
+    Config {
+        name: String,
+        source: PathBuf,
+    },
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Uninstall(id) => write!(f, "uninstall {id}"),
+            Action::Install(id, source) => write!(f, "install   {id} from {}", source.display()),
+            Action::Upgrade {
+                id,
+                from,
+                to,
+                source,
+            } => write!(f, "upgrade   {id} {from} → {to} from {}", source.display()),
+            Action::KeepPendingRemoval(id) => write!(f, "keep      {id}, pending removal"),
+            Action::Enable(id) => write!(f, "enable    {id}"),
+            Action::Disable(id) => write!(f, "disable   {id}"),
+            Action::Config { name, source } => {
+                write!(f, "config    {name} from {}", source.display())
+            }
+        }
+    }
+}
+
+impl Action {
+    fn run(&self) -> Result<()> {
+        match self {
+            Action::Uninstall(id) => module::uninstall_module(id),
+            Action::Install(_, source) | Action::Upgrade { source, .. } => {
+                module::install_module(&source.to_string_lossy())
+            }
+            Action::KeepPendingRemoval(id) => module::restore_pending(id),
+            Action::Enable(id) => module::enable_module(id),
+            Action::Disable(id) => module::disable_module(id),
+            Action::Config { name, source } => {
+                let target = Path::new(defs::MODULE_CONFIG_DIR).join(name);
+                if let Some(parent) = target.parent() {
+                    ensure_dir_exists(parent)?;
+                }
+                fs::copy(source, &target)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The module.prop a source zip would install
+fn source_props(source: &Path) -> Result<HashMap<String, String>> {
+    let file =
+        fs::File::open(source).with_context(|| format!("Failed to open {}", source.display()))?;
+    let mut archive = ZipArchive::new(file)?;
+    let mut content = vec![];
+    archive
+        .by_name("module.prop")
+        .with_context(|| format!("{} has no module.prop", source.display()))?
+        .read_to_end(&mut content)?;
+    let mut props = HashMap::new();
+    PropertiesIter::new_with_encoding(Cursor::new(content), encoding_rs::UTF_8)
+        .read_into(|k, v| {
+            props.insert(k, v.trim().to_string());
+        })
+        .with_context(|| format!("Failed to parse module.prop of {}", source.display()))?;
+    Ok(props)
+}
+
+/// A file below a module dir which a zip can't carry, like a device node or a whiteout
+fn special_file(module_dir: &Path) -> Option<PathBuf> {
+    walkdir::WalkDir::new(module_dir)
+        .into_iter()
+        .flatten()
+        .find(|entry| {
+            let file_type = entry.file_type();
+            !file_type.is_file() && !file_type.is_dir() && !file_type.is_symlink()
+        })
+        .map(|entry| entry.into_path())
+}
+
+fn load(path: &Path) -> Result<Manifest> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    serde_json::from_str(&content).with_context(|| format!("invalid manifest {}", path.display()))
+}
+
+/// Actions which bring the installed modules to the manifest, in the order they have to run
+fn plan(manifest: &Manifest, base: &Path) -> Result<Vec<Action>> {
+    let installed: HashMap<String, HashMap<String, String>> =
+        module::_list_modules(defs::MODULE_DIR)
+            .into_iter()
+            .filter_map(|module| module.get("id").cloned().map(|id| (id, module)))
+            .collect();
+    let flag = |module: &HashMap<String, String>, key: &str| {
+        module.get(key).is_some_and(|value| value == "true")
+    };
+
+    let mut uninstalls = vec![];
+    let mut installs = vec![];
+    let mut toggles = vec![];
+    let mut configs = vec![];
+
+    if manifest.prune {
+        let mut unlisted: Vec<&String> = installed
+            .iter()
+            .filter(|(id, module)| {
+                !flag(module, "remove") && !manifest.modules.iter().any(|m| &m.id == *id)
+            })
+            .map(|(id, _)| id)
+            .collect();
+        unlisted.sort();
+        uninstalls.extend(unlisted.into_iter().cloned().map(Action::Uninstall));
+    }
+
+    for wanted in &manifest.modules {
+        let id = &wanted.id;
+        let source = wanted.source.as_ref().map(|source| base.join(source));
+        let need_source = || -> Result<PathBuf> {
+            let source = source
+                .clone()
+                .with_context(|| format!("module {id} has to be installed, but has no source"))?;
+            let props = source_props(&source)?;
+            let prop = |key: &str| props.get(key).map(String::as_str).unwrap_or_default();
+            ensure!(
+                prop("id") == id,
+                "{} holds module {}, not {id}",
+                source.display(),
+                prop("id")
+            );
+            if let Some(wanted) = &wanted.version_code {
+                ensure!(
+                    prop("versionCode") == wanted,
+                    "{} holds {id} {}, the manifest wants {wanted}",
+                    source.display(),
+                    prop("versionCode")
+                );
+            }
+            Ok(source)
+        };
+        let current = installed.get(id);
+        let version_code = current
+            .and_then(|module| module.get("versionCode").cloned())
+            .unwrap_or_default();
+        // the installer clears the disable flag of what it installs
+        let enabled = match current {
+            None => {
+                installs.push(Action::Install(id.clone(), need_source()?));
+                true
+            }
+            Some(_)
+                if wanted
+                    .version_code
+                    .as_ref()
+                    .is_some_and(|wanted| *wanted != version_code) =>
+            {
+                installs.push(Action::Upgrade {
+                    id: id.clone(),
+                    from: version_code,
+                    to: wanted.version_code.clone().unwrap_or_default(),
+                    source: need_source()?,
+                });
+                true
+            }
+            Some(module) => {
+                if flag(module, "remove") {
+                    installs.push(Action::KeepPendingRemoval(id.clone()));
+                }
+                flag(module, "enabled")
+            }
+        };
+        match (wanted.enabled, enabled) {
+            (true, false) => toggles.push(Action::Enable(id.clone())),
+            (false, true) => toggles.push(Action::Disable(id.clone())),
+            _ => {}
+        }
+
+        for (name, source) in &wanted.config {
+            ensure!(
+                module_backup::is_config_name(name),
+                "config `{name}` of {id} is outside the config dir"
+            );
+            let source = base.join(source);
+            let content = fs::read(&source)
+                .with_context(|| format!("Failed to read config {}", source.display()))?;
+            let target = Path::new(defs::MODULE_CONFIG_DIR).join(name);
+            if fs::read(&target).ok().as_ref() != Some(&content) {
+                configs.push(Action::Config {
+                    name: name.clone(),
+                    source,
+                });
+            }
+        }
+    }
+
+    Ok(uninstalls
+        .into_iter()
+        .chain(installs)
+        .chain(toggles)
+        .chain(configs)
+        .collect())
+}
+
+/// Converge the installed modules to a manifest
+pub fn apply(manifest_path: &Path, dry_run: bool) -> Result<()> {
+    let manifest = load(manifest_path)?;
+    let base = manifest_path.parent().unwrap_or(Path::new("."));
+    let actions = plan(&manifest, base)?;
+    if actions.is_empty() {
+        println!("- Modules already match {}", manifest_path.display());
+        return Ok(());
+    }
+    if dry_run {
+        for action in &actions {
+            println!("{action}");
+        }
+        return Ok(());
+    }
+    module::ensure_boot_completed()?;
+    for (done, action) in actions.iter().enumerate() {
+        println!("- {action}");
+        info!("manifest: {action}");
+        if let Err(e) = action.run() {
+            bail!(
+                "{action} failed after {done} of {} actions: {e:#}",
+                actions.len()
+            );
+        }
+    }
+    println!("- Applied {} actions, reboot to take effect", actions.len());
+    Ok(())
+}
+
+/// Write the installed modules as a manifest, with their config files next to it
+/// and, with `with_sources`, the modules packed as zips
+///
+/// A module with device nodes, whiteouts or other special files is exported without a source,
+/// as its zip would install a different module
+pub fn export(output: &Path, with_sources: bool) -> Result<()> {
+    let base = output.parent().unwrap_or(Path::new("."));
+    let mut manifest = Manifest {
+        prune: true,
+        ..Default::default()
+    };
+    let mut modules = module::_list_modules(defs::MODULE_DIR);
+    modules.sort_by(|a, b| a.get("id").cmp(&b.get("id")));
+    for module in modules {
+        let Some(id) = module.get("id").cloned() else {
+            continue;
+        };
+        if module.get("remove").is_some_and(|remove| remove == "true") {
+            continue;
+        }
+
+        let module_dir = Path::new(defs::MODULE_DIR).join(&id);
+        let special = with_sources.then(|| special_file(&module_dir)).flatten();
+        if let Some(path) = &special {
+            warn!("{} can't be packed in a zip", path.display());
+            println!("- {id} has special files a zip can't hold, exported without its source");
+        }
+        let source = if with_sources && special.is_none() {
+            let source = PathBuf::from("modules").join(format!("{id}.zip"));
+            ensure_dir_exists(base.join("modules"))?;
+            let mut zip = zip::ZipWriter::new(fs::File::create(base.join(&source))?);
+            add_zip_tree(&mut zip, &module_dir, "", &STATE_FILES)?;
+            zip.finish()?;
+            Some(source)
+        } else {
+            None
+        };
+
+        let mut config = BTreeMap::new();
+        for name in module_backup::config_files(&id) {
+            let source = PathBuf::from("config").join(&name);
+            let target = base.join(&source);
+            if let Some(parent) = target.parent() {
+                ensure_dir_exists(parent)?;
+            }
+            fs::copy(Path::new(defs::MODULE_CONFIG_DIR).join(&name), &target)?;
+            config.insert(name, source);
+        }
+
+        manifest.modules.push(ManifestModule {
+            source,
+            version_code: module.get("versionCode").cloned(),
+            enabled: module
+                .get("enabled")
+                .is_none_or(|enabled| enabled == "true"),
+            config,
+            id,
+        });
+    }
+    fs::write(output, serde_json::to_string_pretty(&manifest)?)?;
+    println!(
+        "- Exported {} modules to {}",
+        manifest.modules.len(),
+        output.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,225 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    defs, metamodule,
+    module::{self, ModuleType},
+};
+
+/// How a module was when the system booted
+#[derive(Debug, Serialize, Deserialize)]
+struct BootModule {
+    enabled: bool,
+    version_code: String,
+}
+
+/// What post-fs-data left behind, the base pending changes are told against
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct BootState {
+    modules: BTreeMap<String, BootModule>,
+    metamodule: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+enum Change {
+    Install {
+        id: String,
+        version_code: String,
+    },
+    Update {
+        id: String,
+        from: String,
+        to: String,
+    },
+    Remove {
+        id: String,
+    },
+    Enable {
+        id: String,
+    },
+    Disable {
+        id: String,
+    },
+    Metamodule {
+        from: Option<String>,
+        to: Option<String>,
+    },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let none = "none".to_string();
+        match self {
+            Change::Install { id, version_code } => write!(f, "install  {id} ({version_code})"),
+            Change::Update { id, from, to } => write!(f, "update   {id} {from} → {to}"),
+            Change::Remove { id } => write!(f, "remove   {id}"),
+            Change::Enable { id } => write!(f, "enable   {id}"),
+            Change::Disable { id } => write!(f, "disable  {id}"),
+            Change::Metamodule { from, to } => write!(
+                f,
+                "metamodule {} → {}",
+                from.as_ref().unwrap_or(&none),
+                to.as_ref().unwrap_or(&none)
+            ),
+        }
+    }
+}
+
+fn version_code(module_dir: &Path) -> String {
+    module::read_module_prop(module_dir)
+        .ok()
+        .and_then(|prop| prop.get("versionCode").cloned())
+        .unwrap_or_default()
+}
+
+fn module_id(module_dir: &Path) -> String {
+    module_dir
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+fn metamodule_id() -> Option<String> {
+    metamodule::get_metamodule_path().map(|path| module_id(&path))
+}
+
+/// Snapshot the module states the system booted with, called once updates are applied
+pub fn save_boot_state() -> Result<()> {
+    let mut state = BootState {
+        metamodule: metamodule_id(),
+        ..Default::default()
+    };
+    module::foreach_module(ModuleType::All, |module_dir| {
+        state.modules.insert(
+            module_id(module_dir),
+            BootModule {
+                enabled: !module_dir.join(defs::DISABLE_FILE_NAME).exists(),
+                version_code: version_code(module_dir),
+            },
+        );
+        Ok(())
+    })?;
+    fs::write(defs::BOOT_STATE_FILE, serde_json::to_string(&state)?)?;
+    info!("saved boot state of {} modules", state.modules.len());
+    Ok(())
+}
+
+fn load_boot_state() -> BootState {
+    let Ok(content) = fs::read_to_string(defs::BOOT_STATE_FILE) else {
+        return BootState::default();
+    };
+    serde_json::from_str(&content).unwrap_or_else(|e| {
+        warn!("invalid boot state: {e}");
+        BootState::default()
+    })
+}
+
+/// Modules the system booted with enabled, None before the first snapshot
+pub fn booted_enabled() -> Option<BTreeSet<String>> {
+    let content = fs::read_to_string(defs::BOOT_STATE_FILE).ok()?;
+    let state: BootState = serde_json::from_str(&content).ok()?;
+    Some(
+        state
+            .modules
+            .into_iter()
+            .filter(|(_, module)| module.enabled)
+            .map(|(id, _)| id)
+            .collect(),
+    )
+}
+
+fn module_dirs(dir: &str) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return vec![];
+    };
+    let mut dirs: Vec<PathBuf> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect();
+    dirs.sort();
+    dirs
+}
+
+fn pending_changes() -> Vec<Change> {
+    let boot = load_boot_state();
+    let mut changes = vec![];
+    let mut next_metamodule = metamodule_id();
+
+    for staged in module_dirs(defs::MODULE_UPDATE_DIR) {
+        let id = module_id(&staged);
+        let to = version_code(&staged);
+        // the installer already copied the new module.prop into the live dir
+        let from = boot
+            .modules
+            .get(&id)
+            .map(|module| module.version_code.clone())
+            .or_else(|| {
+                let saved = Path::new(defs::MODULE_PENDING_STATE_DIR).join(&id);
+                saved.exists().then(|| version_code(&saved))
+            });
+        if module::read_module_prop(&staged).is_ok_and(|prop| metamodule::is_metamodule(&prop)) {
+            next_metamodule = Some(id.clone());
+        }
+        changes.push(match from {
+            Some(from) => Change::Update { id, from, to },
+            None => Change::Install {
+                id,
+                version_code: to,
+            },
+        });
+    }
+
+    for module_dir in module_dirs(defs::MODULE_DIR) {
+        let id = module_id(&module_dir);
+        if module_dir.join(defs::REMOVE_FILE_NAME).exists() {
+            if next_metamodule.as_ref() == Some(&id) {
+                next_metamodule = None;
+            }
+            changes.push(Change::Remove { id });
+            continue;
+        }
+        let Some(booted) = boot.modules.get(&id) else {
+            continue;
+        };
+        let enabled = !module_dir.join(defs::DISABLE_FILE_NAME).exists();
+        match (booted.enabled, enabled) {
+            (false, true) => changes.push(Change::Enable { id }),
+            (true, false) => changes.push(Change::Disable { id }),
+            _ => {}
+        }
+    }
+
+    if next_metamodule != boot.metamodule {
+        changes.push(Change::Metamodule {
+            from: boot.metamodule,
+            to: next_metamodule,
+        });
+    }
+    changes
+}
+
+/// What the next boot will change, told against the state of the last one
+pub fn pending(json: bool) -> Result<()> {
+    let changes = pending_changes();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&changes)?);
+        return Ok(());
+    }
+    if changes.is_empty() {
+        println!("- Nothing pending");
+        return Ok(());
+    }
+    for change in &changes {
+        println!("{change}");
+    }
+    Ok(())
+}
@@ -0,0 +1,214 @@
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{defs, module, module_pending, utils::ensure_dir_exists};
+
+const PROFILE_EXTENSION: &str = "json";
+
+/// A named set of modules, everything else is disabled when it's applied
+#[derive(Debug, Serialize, Deserialize)]
+struct Profile {
+    enabled: BTreeSet<String>,
+}
+
+fn profile_path(name: &str) -> Result<PathBuf> {
+    ensure!(
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c)),
+        "profile name may only use letters, digits, `.`, `_` and `-`"
+    );
+    Ok(Path::new(defs::PROFILE_DIR).join(format!("{name}.{PROFILE_EXTENSION}")))
+}
+
+fn load(name: &str) -> Result<Profile> {
+    let path = profile_path(name)?;
+    let content = fs::read_to_string(&path).with_context(|| format!("no profile named {name}"))?;
+    serde_json::from_str(&content).with_context(|| format!("invalid profile {}", path.display()))
+}
+
+/// Installed modules and whether they are enabled, leaving out the ones pending removal
+fn installed() -> Result<Vec<(String, bool)>> {
+    let mut modules = vec![];
+    if !Path::new(defs::MODULE_DIR).exists() {
+        return Ok(modules);
+    }
+    module::foreach_module(module::ModuleType::All, |module_dir| {
+        if module_dir.join(defs::REMOVE_FILE_NAME).exists() {
+            return Ok(());
+        }
+        let Some(id) = module_dir.file_name() else {
+            return Ok(());
+        };
+        modules.push((
+            id.to_string_lossy().to_string(),
+            !module_dir.join(defs::DISABLE_FILE_NAME).exists(),
+        ));
+        Ok(())
+    })?;
+    modules.sort();
+    Ok(modules)
+}
+
+/// Modules to enable and to disable to get from the current flags to a profile
+fn diff(profile: &Profile, installed: &[(String, bool)]) -> (Vec<String>, Vec<String>) {
+    let mut enable = vec![];
+    let mut disable = vec![];
+    for (id, enabled) in installed {
+        match (profile.enabled.contains(id), *enabled) {
+            (true, false) => enable.push(id.clone()),
+            (false, true) => disable.push(id.clone()),
+            _ => {}
+        }
+    }
+    (enable, disable)
+}
+
+/// Save the modules enabled right now as a profile
+pub fn save(name: &str) -> Result<()> {
+    let path = profile_path(name)?;
+    let enabled: BTreeSet<String> = installed()?
+        .into_iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(id, _)| id)
+        .collect();
+    ensure_dir_exists(defs::PROFILE_DIR)?;
+    let count = enabled.len();
+    fs::write(&path, serde_json::to_string_pretty(&Profile { enabled })?)?;
+    println!("- Saved profile {name} with {count} enabled modules");
+    Ok(())
+}
+
+/// Set every module's disable flag to match a profile, undoing all of them if one fails
+pub fn apply(name: &str) -> Result<()> {
+    let profile = load(name)?;
+    let installed = installed()?;
+    for id in &profile.enabled {
+        if !installed.iter().any(|(installed, _)| installed == id) {
+            warn!("profile {name}: module {id} is not installed");
+            println!("- Skipping {id}, it is not installed");
+        }
+    }
+
+    let (enable, disable) = diff(&profile, &installed);
+    if enable.is_empty() && disable.is_empty() {
+        println!("- Profile {name} is already applied");
+        return Ok(());
+    }
+
+    let mut done: Vec<(&str, bool)> = vec![];
+    let toggles = enable
+        .iter()
+        .map(|id| (id.as_str(), true))
+        .chain(disable.iter().map(|id| (id.as_str(), false)));
+    for (id, enabled) in toggles {
+        let result = if enabled {
+            module::enable_module(id)
+        } else {
+            module::disable_module(id)
+        };
+        if let Err(e) = result {
+            for (id, enabled) in done.into_iter().rev() {
+                let undo = if enabled {
+                    module::disable_module(id)
+                } else {
+                    module::enable_module(id)
+                };
+                if let Err(e) = undo {
+                    warn!("Failed to restore the state of {id}: {e}");
+                }
+            }
+            bail!("Failed to apply profile {name} at {id}: {e}");
+        }
+        done.push((id, enabled));
+    }
+
+    info!("applied profile {name}: enable {enable:?}, disable {disable:?}");
+    for id in &enable {
+        println!("- Enabled {id}");
+    }
+    for id in &disable {
+        println!("- Disabled {id}");
+    }
+    println!("- Profile {name} applied, takes effect after reboot");
+    Ok(())
+}
+
+pub fn list() -> Result<()> {
+    let Ok(dir) = fs::read_dir(defs::PROFILE_DIR) else {
+        return Ok(());
+    };
+    let installed = installed()?;
+    let mut names: Vec<String> = dir
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == PROFILE_EXTENSION))
+        .filter_map(|path| path.file_stem().map(|s| s.to_string_lossy().to_string()))
+        .collect();
+    names.sort();
+    for name in names {
+        match load(&name) {
+            Ok(profile) => {
+                let (enable, disable) = diff(&profile, &installed);
+                let applied = enable.is_empty() && disable.is_empty();
+                let marker = if applied { "*" } else { " " };
+                println!("{marker} {name}\t{} modules", profile.enabled.len());
+            }
+            Err(e) => warn!("{e:#}"),
+        }
+    }
+    Ok(())
+}
+
+pub fn delete(name: &str) -> Result<()> {
+    let path = profile_path(name)?;
+    ensure!(path.exists(), "no profile named {name}");
+    fs::remove_file(path)?;
+    println!("- Deleted profile {name}");
+    Ok(())
+}
+
+/// Whether a profile is applied, or with `reboot_needed`, whether the booted system runs it yet
+pub fn status(name: &str, reboot_needed: bool) -> Result<()> {
+    let profile = load(name)?;
+    let installed = installed()?;
+    if reboot_needed {
+        let Some(booted) = module_pending::booted_enabled() else {
+            bail!("the state of the running system is unknown until the next boot");
+        };
+        let wanted: BTreeSet<&String> = installed
+            .iter()
+            .map(|(id, _)| id)
+            .filter(|id| profile.enabled.contains(*id))
+            .collect();
+        let running: BTreeSet<&String> = installed
+            .iter()
+            .map(|(id, _)| id)
+            .filter(|id| booted.contains(*id))
+            .collect();
+        println!("{}", if wanted == running { "no" } else { "yes" });
+        return Ok(());
+    }
+
+    let (enable, disable) = diff(&profile, &installed);
+    if enable.is_empty() && disable.is_empty() {
+        println!("- Profile {name} is applied");
+        return Ok(());
+    }
+    println!("- Profile {name} is not applied");
+    for id in enable {
+        println!("  enable  {id}");
+    }
+    for id in disable {
+        println!("  disable {id}");
+    }
+    Ok(())
+}
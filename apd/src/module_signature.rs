@@ -0,0 +1,308 @@
+//! Signed module zips
+//!
+//! A signed zip carries `META-INF/apd/signature`, the hex ed25519 signature of its digest:
+//! one `<sha256>  <type> <mode>  <name>` line per file entry, sorted by name, leaving out the
+//! signature itself. The type is `l` for symlinks and `f` otherwise, the mode is the octal
+//! permission bits of the entry or `-` when it has none, as both decide what gets extracted.
+//! Signatures are checked against the public keys in the trust store.
+
+use std::{
+    fmt, fs,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use ed25519_dalek::{PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH, Signature, VerifyingKey};
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use zip::ZipArchive;
+
+use crate::{defs, utils::ensure_dir_exists};
+
+const SIGNATURE_ENTRY: &str = "META-INF/apd/signature";
+const KEY_EXTENSION: &str = "pub";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SignaturePolicy {
+    /// install any zip, signed or not
+    #[default]
+    Off,
+    /// install zips without a trusted signature, but say so and record it
+    Warn,
+    /// only install zips signed by a trusted key
+    Enforce,
+}
+
+impl fmt::Display for SignaturePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignaturePolicy::Off => write!(f, "off"),
+            SignaturePolicy::Warn => write!(f, "warn"),
+            SignaturePolicy::Enforce => write!(f, "enforce"),
+        }
+    }
+}
+
+/// What the signature of a zip proved, as recorded in the installed module
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignatureStatus {
+    Trusted(String),
+    Unsigned,
+    Untrusted,
+}
+
+impl fmt::Display for SignatureStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureStatus::Trusted(key) => write!(f, "trusted:{key}"),
+            SignatureStatus::Unsigned => write!(f, "unsigned"),
+            SignatureStatus::Untrusted => write!(f, "untrusted"),
+        }
+    }
+}
+
+pub fn policy() -> SignaturePolicy {
+    let Ok(policy) = fs::read_to_string(defs::SIGNATURE_POLICY_FILE) else {
+        return SignaturePolicy::default();
+    };
+    match clap::ValueEnum::from_str(policy.trim(), true) {
+        Ok(policy) => policy,
+        Err(e) => {
+            // an unreadable policy must not silently turn enforcement off
+            warn!("invalid signature policy, enforcing: {e}");
+            SignaturePolicy::Enforce
+        }
+    }
+}
+
+pub fn set_policy(policy: SignaturePolicy) -> Result<()> {
+    ensure_dir_exists(defs::WORKING_DIR)?;
+    fs::write(defs::SIGNATURE_POLICY_FILE, policy.to_string())?;
+    Ok(())
+}
+
+fn key_path(name: &str) -> PathBuf {
+    Path::new(defs::TRUSTED_KEYS_DIR).join(format!("{name}.{KEY_EXTENSION}"))
+}
+
+fn parse_key(hex_key: &str) -> Result<VerifyingKey> {
+    let bytes: [u8; PUBLIC_KEY_LENGTH] = hex::decode(hex_key.trim())
+        .context("key is not hex")?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("key must be {PUBLIC_KEY_LENGTH} bytes"))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|e| anyhow::anyhow!("invalid ed25519 key: {e}"))
+}
+
+fn trusted_keys() -> Vec<(String, VerifyingKey)> {
+    let Ok(dir) = fs::read_dir(defs::TRUSTED_KEYS_DIR) else {
+        return vec![];
+    };
+    let mut keys = vec![];
+    for entry in dir.flatten() {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != KEY_EXTENSION) {
+            continue;
+        }
+        let Some(name) = path.file_stem().map(|s| s.to_string_lossy().to_string()) else {
+            continue;
+        };
+        match fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|k| parse_key(&k))
+        {
+            Ok(key) => keys.push((name, key)),
+            Err(e) => warn!("skip trusted key {}: {e}", path.display()),
+        }
+    }
+    keys.sort_by(|a, b| a.0.cmp(&b.0));
+    keys
+}
+
+/// Trust a public key, given as hex or as a file holding it
+pub fn add_key(name: &str, key: &str) -> Result<()> {
+    ensure!(
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c)),
+        "key name may only use letters, digits, `.`, `_` and `-`"
+    );
+    let hex_key = if Path::new(key).is_file() {
+        fs::read_to_string(key)?
+    } else {
+        key.to_string()
+    };
+    let key = parse_key(&hex_key)?;
+    ensure_dir_exists(defs::TRUSTED_KEYS_DIR)?;
+    fs::write(key_path(name), hex::encode(key.to_bytes()))?;
+    println!("- Trusted key {name} added");
+    Ok(())
+}
+
+pub fn remove_key(name: &str) -> Result<()> {
+    let path = key_path(name);
+    ensure!(path.exists(), "no trusted key named {name}");
+    fs::remove_file(path)?;
+    println!("- Trusted key {name} removed");
+    Ok(())
+}
+
+pub fn list_keys() -> Result<()> {
+    println!("policy: {}", policy());
+    for (name, key) in trusted_keys() {
+        println!("{name}\t{}", hex::encode(key.to_bytes()));
+    }
+    Ok(())
+}
+
+/// The message a module zip is signed over
+fn zip_digest(archive: &mut ZipArchive<fs::File>) -> Result<String> {
+    let mut names: Vec<String> = archive
+        .file_names()
+        .filter(|name| *name != SIGNATURE_ENTRY && !name.ends_with('/'))
+        .map(str::to_string)
+        .collect();
+    names.sort();
+    // only one of the entries sharing a name would be covered by the signature
+    let total = names.len();
+    names.dedup();
+    ensure!(names.len() == total, "zip has duplicate entries");
+    let mut digest = String::new();
+    for name in names {
+        let mut entry = archive.by_name(&name)?;
+        let kind = if entry.is_symlink() { "l" } else { "f" };
+        let mode = entry
+            .unix_mode()
+            .map_or("-".to_string(), |mode| format!("{:04o}", mode & 0o7777));
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut entry, &mut hasher)?;
+        digest.push_str(&format!(
+            "{}  {kind} {mode}  {name}\n",
+            hex::encode(hasher.finalize())
+        ));
+    }
+    Ok(digest)
+}
+
+pub fn check_zip(zip: &Path) -> Result<SignatureStatus> {
+    let file = fs::File::open(zip).with_context(|| format!("Failed to open {}", zip.display()))?;
+    let mut archive = ZipArchive::new(file)?;
+    let signature = match archive.by_name(SIGNATURE_ENTRY) {
+        Ok(mut entry) => {
+            let mut content = String::new();
+            entry.read_to_string(&mut content)?;
+            content
+        }
+        Err(_) => return Ok(SignatureStatus::Unsigned),
+    };
+    let Ok(signature) = hex::decode(signature.trim()) else {
+        warn!("{SIGNATURE_ENTRY} is not hex");
+        return Ok(SignatureStatus::Untrusted);
+    };
+    let Ok(signature): Result<[u8; SIGNATURE_LENGTH], _> = signature.try_into() else {
+        warn!("{SIGNATURE_ENTRY} is not an ed25519 signature");
+        return Ok(SignatureStatus::Untrusted);
+    };
+    let signature = Signature::from_bytes(&signature);
+
+    let digest = zip_digest(&mut archive)?;
+    for (name, key) in trusted_keys() {
+        if key.verify_strict(digest.as_bytes(), &signature).is_ok() {
+            return Ok(SignatureStatus::Trusted(name));
+        }
+    }
+    Ok(SignatureStatus::Untrusted)
+}
+
+pub fn check(zip: &Path) -> Result<()> {
+    println!("{}", check_zip(zip)?);
+    Ok(())
+}
+
+/// Check a zip against the policy before installing, None when the policy is off
+pub fn check_install(zip: &Path) -> Result<Option<SignatureStatus>> {
+    let policy = policy();
+    if policy == SignaturePolicy::Off {
+        return Ok(None);
+    }
+    let status = check_zip(zip)?;
+    info!("signature of {}: {status}", zip.display());
+    match (&status, policy) {
+        (SignatureStatus::Trusted(key), _) => println!("- Signed by trusted key {key}"),
+        (_, SignaturePolicy::Enforce) => {
+            println!("\n❌ Installation Blocked");
+            println!("┌────────────────────────────────");
+            println!("│ The module is {status}");
+            println!("│");
+            println!("│ Only modules signed by a trusted key may be installed,");
+            println!("│ see apd trust list");
+            println!("└─────────────────────────────────\n");
+            bail!("Module signature is {status}");
+        }
+        _ => println!("- Warning: the module is {status}"),
+    }
+    Ok(Some(status))
+}
+
+/// Check a module staged from a backup against the policy. Its files don't come with the zip
+/// they were signed in, so enforcement refuses it
+pub fn check_restore(id: &str) -> Result<()> {
+    match policy() {
+        SignaturePolicy::Off => {}
+        SignaturePolicy::Warn => {
+            println!("- Warning: the signature of {id} can't be checked in a backup");
+        }
+        SignaturePolicy::Enforce => {
+            bail!("{id} can't be restored from a backup while signatures are enforced")
+        }
+    }
+    Ok(())
+}
+
+/// Apply the policy to a kept version before it's staged again, by the signature recorded when
+/// it was installed, as long as its key is still trusted
+pub fn check_rollback(snapshot: &Path) -> Result<()> {
+    let policy = policy();
+    if policy == SignaturePolicy::Off {
+        return Ok(());
+    }
+    let (status, signer) = recorded(snapshot);
+    let keys = trusted_keys();
+    match signer {
+        Some(key) if status == "trusted" && keys.iter().any(|(name, _)| *name == key) => {
+            println!("- Signed by trusted key {key}");
+        }
+        _ if policy == SignaturePolicy::Enforce => {
+            bail!(
+                "the kept version is {status}, only modules signed by a trusted key may be installed"
+            )
+        }
+        _ => println!("- Warning: the kept version is {status}"),
+    }
+    Ok(())
+}
+
+/// Remember how a module was signed, `apd module list` reports it
+///
+/// With no status, a status file shipped in the zip itself is dropped
+pub fn record(module_dir: &Path, status: Option<&SignatureStatus>) -> Result<()> {
+    let path = module_dir.join(defs::SIGNATURE_STATUS_FILE_NAME);
+    match status {
+        Some(status) => fs::write(path, status.to_string())?,
+        None if path.exists() => fs::remove_file(path)?,
+        None => {}
+    }
+    Ok(())
+}
+
+/// The recorded signature of an installed module and the key which signed it
+pub fn recorded(module_dir: &Path) -> (String, Option<String>) {
+    let Ok(status) = fs::read_to_string(module_dir.join(defs::SIGNATURE_STATUS_FILE_NAME)) else {
+        return ("unchecked".to_string(), None);
+    };
+    match status.trim().split_once(':') {
+        Some((status, key)) => (status.to_string(), Some(key.to_string())),
+        None => (status.trim().to_string(), None),
+    }
+}
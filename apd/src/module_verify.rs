@@ -0,0 +1,389 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Cursor, Read},
+    path::{Component, Path},
+    sync::LazyLock,
+};
+
+use anyhow::{Context, Result, bail};
+use java_properties::PropertiesIter;
+use regex::Regex;
+use serde::Serialize;
+use zip::ZipArchive;
+
+use crate::{defs, sepolicy};
+
+/// module.prop keys the manager can't do without
+const REQUIRED_PROPS: [&str; 2] = ["id", "versionCode"];
+/// installs without them always worked, the manager shows the id instead
+const RECOMMENDED_PROPS: [&str; 4] = ["name", "version", "author", "description"];
+
+const UPDATE_BINARY: &str = "META-INF/com/google/android/update-binary";
+/// Scripts the installer and the boot stages run with the shell
+const STAGE_SCRIPTS: [&str; 7] = [
+    "customize.sh",
+    "post-fs-data.sh",
+    "service.sh",
+    "post-mount.sh",
+    "boot-completed.sh",
+    "uninstall.sh",
+    "action.sh",
+];
+
+static ID_FORMAT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[a-zA-Z][a-zA-Z0-9._-]+$").expect("valid id regex"));
+const METAMODULE_SCRIPTS: [&str; 3] = [
+    defs::METAMODULE_MOUNT_SCRIPT,
+    defs::METAMODULE_METAINSTALL_SCRIPT,
+    defs::METAMODULE_METAUNINSTALL_SCRIPT,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Finding {
+    pub severity: Severity,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub findings: Vec<Finding>,
+}
+
+impl Check {
+    fn new(name: &'static str) -> Self {
+        Check {
+            name,
+            findings: vec![],
+        }
+    }
+
+    fn error(&mut self, message: impl Into<String>) {
+        self.findings.push(Finding {
+            severity: Severity::Error,
+            message: message.into(),
+        });
+    }
+
+    fn warn(&mut self, message: impl Into<String>) {
+        self.findings.push(Finding {
+            severity: Severity::Warning,
+            message: message.into(),
+        });
+    }
+
+    fn count(&self, severity: Severity) -> usize {
+        self.findings
+            .iter()
+            .filter(|finding| finding.severity == severity)
+            .count()
+    }
+}
+
+/// What `apd module verify` found in a module zip, check by check
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub id: Option<String>,
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    pub fn errors(&self) -> usize {
+        self.checks.iter().map(|c| c.count(Severity::Error)).sum()
+    }
+
+    pub fn warnings(&self) -> usize {
+        self.checks.iter().map(|c| c.count(Severity::Warning)).sum()
+    }
+
+    /// Every check with its findings; `quiet` leaves out the checks which passed
+    pub fn print(&self, quiet: bool) {
+        for check in &self.checks {
+            let (errors, warnings) = (check.count(Severity::Error), check.count(Severity::Warning));
+            match (errors, warnings) {
+                (0, 0) if quiet => continue,
+                (0, 0) => println!("- {}: ok", check.name),
+                (0, warnings) => println!("- {}: {warnings} warnings", check.name),
+                (errors, warnings) => {
+                    println!("- {}: {errors} errors, {warnings} warnings", check.name)
+                }
+            }
+            for finding in &check.findings {
+                let severity = match finding.severity {
+                    Severity::Warning => "warning",
+                    Severity::Error => "error",
+                };
+                println!("  {severity}: {}", finding.message);
+            }
+        }
+    }
+}
+
+fn read_entry(archive: &mut ZipArchive<fs::File>, name: &str) -> Option<Vec<u8>> {
+    let mut entry = archive.by_name(name).ok()?;
+    let mut content = vec![];
+    entry.read_to_end(&mut content).ok()?;
+    Some(content)
+}
+
+fn check_module_prop(archive: &mut ZipArchive<fs::File>) -> (Check, HashMap<String, String>) {
+    let mut check = Check::new("module.prop");
+    let mut props = HashMap::new();
+    let Some(content) = read_entry(archive, "module.prop") else {
+        check.error("module.prop is missing");
+        return (check, props);
+    };
+    let parsed = PropertiesIter::new_with_encoding(Cursor::new(content), encoding_rs::UTF_8)
+        .read_into(|k, v| {
+            props.insert(k, v.trim().to_string());
+        });
+    if let Err(e) = parsed {
+        check.error(format!("unreadable: {e}"));
+        return (check, props);
+    }
+
+    for key in REQUIRED_PROPS {
+        if props.get(key).is_none_or(|value| value.is_empty()) {
+            check.error(format!("required field {key} is missing"));
+        }
+    }
+    for key in RECOMMENDED_PROPS {
+        if !props.contains_key(key) {
+            check.warn(format!("{key} is not set"));
+        }
+    }
+    if let Some(id) = props.get("id").filter(|id| !id.is_empty())
+        && !is_valid_id(id)
+    {
+        check.error(format!(
+            "id `{id}` must start with a letter and only use letters, digits, `.`, `_` and `-`"
+        ));
+    }
+    if let Some(version_code) = props.get("versionCode").filter(|v| !v.is_empty())
+        && version_code.parse::<i64>().is_err()
+    {
+        check.error(format!("versionCode `{version_code}` is not a number"));
+    }
+    (check, props)
+}
+
+/// Whether a module id has the form installers accept, which also keeps it a plain dir name
+pub fn is_valid_id(id: &str) -> bool {
+    ID_FORMAT.is_match(id)
+}
+
+/// Whether a relative symlink target climbs out of the module dir
+fn escapes(entry: &Path, target: &Path) -> bool {
+    let mut depth = entry.components().count() as isize - 1;
+    for component in target.components() {
+        match component {
+            Component::ParentDir => depth -= 1,
+            Component::Normal(_) => depth += 1,
+            _ => {}
+        }
+        if depth < 0 {
+            return true;
+        }
+    }
+    false
+}
+
+fn check_entries(archive: &mut ZipArchive<fs::File>) -> Check {
+    let mut check = Check::new("zip entries");
+    for index in 0..archive.len() {
+        let mut entry = match archive.by_index(index) {
+            Ok(entry) => entry,
+            Err(e) => {
+                check.error(format!("entry {index} is unreadable: {e}"));
+                continue;
+            }
+        };
+        let name = entry.name().to_string();
+        if name.starts_with('/') {
+            check.error(format!("{name}: absolute path"));
+            continue;
+        }
+        let Some(path) = entry.enclosed_name() else {
+            check.error(format!("{name}: path escapes the module"));
+            continue;
+        };
+        let mode = entry.unix_mode().unwrap_or(0);
+        if mode & 0o6000 != 0 {
+            check.error(format!("{name}: setuid/setgid bits ({:o})", mode & 0o7777));
+        }
+        if entry.is_symlink() {
+            let mut target = String::new();
+            if entry.read_to_string(&mut target).is_err() {
+                check.error(format!("{name}: unreadable symlink target"));
+                continue;
+            }
+            let target = Path::new(&target);
+            if target.is_absolute() {
+                check.warn(format!("{name}: absolute symlink to {}", target.display()));
+            } else if escapes(&path, target) {
+                check.error(format!(
+                    "{name}: symlink to {} escapes the module",
+                    target.display()
+                ));
+            }
+        }
+    }
+    check
+}
+
+fn check_scripts(archive: &mut ZipArchive<fs::File>) -> Check {
+    let mut check = Check::new("scripts");
+    let scripts: Vec<String> = archive
+        .file_names()
+        .filter(|name| name.ends_with(".sh") || *name == UPDATE_BINARY)
+        .map(str::to_string)
+        .collect();
+    for name in scripts {
+        let Some(content) = read_entry(archive, &name) else {
+            check.error(format!("{name}: unreadable"));
+            continue;
+        };
+        if !content.windows(2).any(|pair| pair == b"\r\n") {
+            continue;
+        }
+        // payload scripts may be meant for other tools, only the ones apd runs must work
+        let runs = name == UPDATE_BINARY
+            || STAGE_SCRIPTS.contains(&name.as_str())
+            || METAMODULE_SCRIPTS.contains(&name.as_str());
+        if runs {
+            check.error(format!("{name}: CRLF line endings, the shell can't run it"));
+        } else {
+            check.warn(format!("{name}: CRLF line endings"));
+        }
+    }
+    check
+}
+
+fn check_sepolicy(archive: &mut ZipArchive<fs::File>) -> Check {
+    let mut check = Check::new("sepolicy.rule");
+    let Some(content) = read_entry(archive, "sepolicy.rule") else {
+        return check;
+    };
+    let content = String::from_utf8_lossy(&content);
+    for warning in sepolicy::module_rule_warnings(&content) {
+        check.warn(warning);
+    }
+    let block_level = sepolicy::lint_block_level();
+    for finding in sepolicy::lint_sepolicy(&content) {
+        if block_level.is_some_and(|level| finding.risk >= level) {
+            check.error(format!("{finding} (blocked by apd sepolicy lint-block)"));
+        } else {
+            check.warn(finding.to_string());
+        }
+    }
+    check
+}
+
+fn check_system_prop(archive: &mut ZipArchive<fs::File>) -> Check {
+    let mut check = Check::new("system.prop");
+    let Some(content) = read_entry(archive, "system.prop") else {
+        return check;
+    };
+    let key_format = Regex::new(r"^[A-Za-z0-9_.\-@:]+$").expect("valid prop regex");
+    for (index, line) in String::from_utf8_lossy(&content).lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((key, _)) if key_format.is_match(key.trim()) => {}
+            Some((key, _)) => {
+                check.warn(format!("line {}: invalid key `{}`", index + 1, key.trim()))
+            }
+            None => check.warn(format!("line {}: expected key=value", index + 1)),
+        }
+    }
+    check
+}
+
+fn check_metamodule(archive: &mut ZipArchive<fs::File>, props: &HashMap<String, String>) -> Check {
+    let mut check = Check::new("metamodule");
+    let flag = props.get("metamodule").map(String::as_str);
+    if let Some(flag) = flag
+        && !["0", "1"].contains(&flag)
+        && !flag.eq_ignore_ascii_case("true")
+        && !flag.eq_ignore_ascii_case("false")
+    {
+        check.warn(format!("metamodule=`{flag}` is read as false"));
+    }
+    let is_metamodule = crate::metamodule::is_metamodule(props);
+    let shipped: Vec<&str> = METAMODULE_SCRIPTS
+        .into_iter()
+        .filter(|script| archive.index_for_name(script).is_some())
+        .collect();
+    if is_metamodule && !shipped.contains(&defs::METAMODULE_MOUNT_SCRIPT) {
+        check.warn(format!(
+            "metamodule without {}, modules won't be mounted",
+            defs::METAMODULE_MOUNT_SCRIPT
+        ));
+    }
+    if !is_metamodule {
+        for script in shipped {
+            check.warn(format!(
+                "{script} is ignored, the module is not a metamodule"
+            ));
+        }
+    }
+    check
+}
+
+/// Check a module zip without installing it, Err only when it can't be read at all
+pub fn verify_zip(zip: &Path) -> Result<Report> {
+    let file = fs::File::open(zip).with_context(|| format!("Failed to open {}", zip.display()))?;
+    let mut archive = ZipArchive::new(file).context("not a zip file")?;
+
+    let (prop_check, props) = check_module_prop(&mut archive);
+    let checks = vec![
+        prop_check,
+        check_entries(&mut archive),
+        check_scripts(&mut archive),
+        check_sepolicy(&mut archive),
+        check_system_prop(&mut archive),
+        check_metamodule(&mut archive, &props),
+    ];
+    Ok(Report {
+        id: props.get("id").cloned(),
+        checks,
+    })
+}
+
+/// Check the entries of a module backup, which holds module dirs rather than a module zip
+pub fn verify_backup(zip: &Path) -> Result<Report> {
+    let file = fs::File::open(zip).with_context(|| format!("Failed to open {}", zip.display()))?;
+    let mut archive = ZipArchive::new(file).context("not a zip file")?;
+    Ok(Report {
+        id: None,
+        checks: vec![check_entries(&mut archive)],
+    })
+}
+
+pub fn verify(zip: &Path, json: bool, backup: bool) -> Result<()> {
+    let report = if backup {
+        verify_backup(zip)?
+    } else {
+        verify_zip(zip)?
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        report.print(false);
+        println!("{} errors, {} warnings", report.errors(), report.warnings());
+    }
+    if report.errors() > 0 {
+        bail!("{} failed verification", zip.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,201 @@
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, ensure};
+use log::warn;
+use procfs::process::Process;
+use rustix::mount::{UnmountFlags, unmount};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    defs::{self, AP_MAGIC_MOUNT_SOURCE, AP_OVERLAY_SOURCE},
+    utils::switch_mnt_ns,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MountOp {
+    /// a module file bound over the real one
+    Bind,
+    /// a stock file or untouched dir bound back into a tmpfs skeleton
+    Mirror,
+    /// a tmpfs skeleton moved over a real dir
+    Tmpfs,
+}
+
+/// One mount made by magic mount, by the real path it ends up at
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MountRecord {
+    pub op: MountOp,
+    pub target: PathBuf,
+    pub source: Option<PathBuf>,
+    pub module: Option<String>,
+    /// lives inside a tmpfs skeleton and goes away together with it
+    pub in_tmpfs: bool,
+}
+
+impl MountRecord {
+    pub fn new(op: MountOp, target: &Path, source: Option<&Path>, in_tmpfs: bool) -> Self {
+        MountRecord {
+            op,
+            target: target.to_path_buf(),
+            source: source.map(Path::to_path_buf),
+            module: source.and_then(module_of_path),
+            in_tmpfs,
+        }
+    }
+}
+
+/// `<id>` of a path below the module dir or the pending update dir
+fn module_of_path(path: &Path) -> Option<String> {
+    let relative = path
+        .strip_prefix(defs::MODULE_DIR)
+        .or_else(|_| path.strip_prefix(defs::MODULE_UPDATE_DIR))
+        .ok()?;
+    let id = relative.components().next()?;
+    Some(id.as_os_str().to_string_lossy().to_string())
+}
+
+/// `<id>` of a mountinfo root, which is relative to the filesystem holding /data/adb
+fn module_of_root(root: &str) -> Option<&str> {
+    const MODULES: &str = "/adb/modules/";
+    let start = root.find(MODULES)? + MODULES.len();
+    root[start..].split('/').next().filter(|id| !id.is_empty())
+}
+
+pub fn write_journal(records: &[MountRecord]) -> Result<()> {
+    if records.is_empty() {
+        if Path::new(defs::MOUNT_JOURNAL_FILE).exists() {
+            fs::remove_file(defs::MOUNT_JOURNAL_FILE)?;
+        }
+        return Ok(());
+    }
+    let mut file = fs::File::create(defs::MOUNT_JOURNAL_FILE)
+        .with_context(|| format!("Failed to create {}", defs::MOUNT_JOURNAL_FILE))?;
+    for record in records {
+        writeln!(file, "{}", serde_json::to_string(record)?)?;
+    }
+    Ok(())
+}
+
+pub fn read_journal() -> Result<Vec<MountRecord>> {
+    let file = match fs::File::open(defs::MOUNT_JOURNAL_FILE) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).context("Failed to open mount journal"),
+    };
+    let mut records = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        match serde_json::from_str(&line) {
+            Ok(record) => records.push(record),
+            Err(e) => warn!("skip bad mount journal line: {e}"),
+        }
+    }
+    Ok(records)
+}
+
+/// Detach the recorded mounts in reverse order, all of them or those of one module
+pub fn unmount_modules(module: Option<&str>) -> Result<()> {
+    let records = read_journal()?;
+    if records.is_empty() {
+        println!("no magic mount recorded");
+        return Ok(());
+    }
+    // the mounts were made in init's namespace
+    switch_mnt_ns(1)?;
+
+    let mut remaining = vec![];
+    let mut in_tmpfs = vec![];
+    let mut failed_tmpfs = vec![];
+    for (index, record) in records.into_iter().enumerate().rev() {
+        let selected = module.is_none_or(|id| record.module.as_deref() == Some(id));
+        if !selected {
+            remaining.push((index, record));
+            continue;
+        }
+        if module.is_some() && (record.in_tmpfs || record.op == MountOp::Tmpfs) {
+            // the skeleton may hold other modules' files too
+            println!(
+                "- {} is part of a tmpfs skeleton, unmount everything to remove it",
+                record.target.display()
+            );
+            remaining.push((index, record));
+            continue;
+        }
+        if record.in_tmpfs {
+            in_tmpfs.push((index, record));
+            continue;
+        }
+        match unmount(&record.target, UnmountFlags::DETACH) {
+            Ok(()) => println!("- unmounted {}", record.target.display()),
+            Err(e) => {
+                warn!("failed to unmount {}: {e}", record.target.display());
+                if record.op == MountOp::Tmpfs {
+                    failed_tmpfs.push(record.target.clone());
+                }
+                remaining.push((index, record));
+            }
+        }
+    }
+    // mounts inside a skeleton are only gone if the skeleton is
+    remaining.extend(in_tmpfs.into_iter().filter(|(_, record)| {
+        failed_tmpfs
+            .iter()
+            .any(|tmpfs| record.target.starts_with(tmpfs))
+    }));
+    remaining.sort_by_key(|(index, _)| *index);
+    let remaining: Vec<MountRecord> = remaining.into_iter().map(|(_, record)| record).collect();
+    write_journal(&remaining)
+}
+
+/// Undo the mounts of one module, refusing when they are part of a tmpfs skeleton
+pub fn unapply_module(id: &str) -> Result<()> {
+    let (own, mut others): (Vec<MountRecord>, Vec<MountRecord>) = read_journal()?
+        .into_iter()
+        .partition(|record| record.module.as_deref() == Some(id));
+    if own.is_empty() {
+        println!("- Module {id} has no mounts");
+        return Ok(());
+    }
+    ensure!(
+        !own.iter()
+            .any(|record| record.in_tmpfs || record.op == MountOp::Tmpfs),
+        "module {id} is mounted through a tmpfs skeleton, reboot to remove it"
+    );
+
+    let mut unmounted = 0;
+    for record in own.into_iter().rev() {
+        match unmount(&record.target, UnmountFlags::DETACH) {
+            Ok(()) => unmounted += 1,
+            Err(e) => {
+                warn!("failed to unmount {}: {e}", record.target.display());
+                others.push(record);
+            }
+        }
+    }
+    println!("- Removed {unmounted} mounts of {id}");
+    write_journal(&others)
+}
+
+/// Print the mounts of init's namespace which belong to modules or to apd
+pub fn list_mounts() -> Result<()> {
+    let mounts = Process::new(1)?.mountinfo()?;
+    for mount in mounts {
+        let source = mount.mount_source.as_deref();
+        let owner = if let Some(id) = module_of_root(&mount.root) {
+            id
+        } else if source == Some(AP_MAGIC_MOUNT_SOURCE) {
+            "[tmpfs]"
+        } else if source == Some(AP_OVERLAY_SOURCE) && mount.fs_type == "overlay" {
+            "[overlayfs]"
+        } else {
+            continue;
+        };
+        println!("{owner}\t{}\t{}", mount.mount_point.display(), mount.root);
+    }
+    Ok(())
+}
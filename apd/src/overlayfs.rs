@@ -0,0 +1,243 @@
+use std::{
+    ffi::CString,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use extattr::{Flags as XattrFlags, lsetxattr};
+use procfs::process::Process;
+use rustix::mount::{MountFlags, UnmountFlags, mount, mount_bind, unmount};
+
+use crate::{
+    defs::AP_OVERLAY_SOURCE,
+    magic_mount::{REPLACE_DIR_FILE_NAME, REPLACE_DIR_XATTR, mountable_modules},
+    partition::{self, Partition},
+};
+
+// the mount data is a single page
+const MAX_MOUNT_DATA_LEN: usize = 4000;
+
+fn overlay_supported() -> bool {
+    fs::read_to_string("/proc/filesystems").is_ok_and(|filesystems| {
+        filesystems
+            .lines()
+            .any(|line| line.split_whitespace().last() == Some("overlay"))
+    })
+}
+
+/// overlayfs knows opaque dirs by xattr only, so translate the `.replace` marker
+fn mark_replace_dirs(dir: &Path) -> Result<()> {
+    for entry in dir.read_dir()?.flatten() {
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let path = entry.path();
+        if path.join(REPLACE_DIR_FILE_NAME).exists() {
+            log::debug!("{} need replace", path.display());
+            lsetxattr(&path, REPLACE_DIR_XATTR, "y", XattrFlags::empty())
+                .with_context(|| format!("mark {} opaque", path.display()))?;
+        }
+        mark_replace_dirs(&path)?;
+    }
+    Ok(())
+}
+
+fn mount_overlayfs(lower_dirs: &[String], lowest: &str, dest: &Path) -> Result<()> {
+    let lowerdir = lower_dirs
+        .iter()
+        .map(String::as_str)
+        .chain([lowest])
+        .collect::<Vec<_>>()
+        .join(":");
+    let data = format!("lowerdir={lowerdir}");
+    ensure!(
+        data.len() <= MAX_MOUNT_DATA_LEN,
+        "too many layers for {}",
+        dest.display()
+    );
+    log::debug!("mount overlayfs on {}, {data}", dest.display());
+    let data = CString::new(data)?;
+    mount(
+        AP_OVERLAY_SOURCE,
+        dest,
+        "overlay",
+        MountFlags::empty(),
+        data.as_c_str(),
+    )
+    .with_context(|| format!("mount overlayfs on {}", dest.display()))?;
+    Ok(())
+}
+
+/// Restore a mount which lived below the partition and is now hidden by the overlay
+fn mount_overlay_child(
+    mount_point: &Path,
+    relative: &Path,
+    module_roots: &[String],
+    stock_root: &Path,
+) -> Result<()> {
+    let mut lower_dirs = vec![];
+    for lower in module_roots {
+        let lower_dir = Path::new(lower).join(relative);
+        if lower_dir.is_dir() {
+            lower_dirs.push(lower_dir.to_string_lossy().to_string());
+        } else if lower_dir.exists() {
+            // a module file replaces the whole mount
+            return Ok(());
+        }
+    }
+    if lower_dirs.is_empty() {
+        log::debug!(
+            "bind stock {} -> {}",
+            stock_root.display(),
+            mount_point.display()
+        );
+        mount_bind(stock_root, mount_point)?;
+        return Ok(());
+    }
+    if !stock_root.is_dir() {
+        return Ok(());
+    }
+    if let Err(e) = mount_overlayfs(&lower_dirs, &stock_root.to_string_lossy(), mount_point) {
+        log::warn!("{e:#}, fallback to bind mount");
+        mount_bind(stock_root, mount_point)?;
+    }
+    Ok(())
+}
+
+fn mount_partition(root: &Path, module_roots: &[String]) -> Result<()> {
+    log::info!("mount overlayfs for {}", root.display());
+    // the cwd keeps the stock tree reachable once the overlay covers it
+    std::env::set_current_dir(root)?;
+    let stock_root = Path::new(".");
+
+    let mut children: Vec<PathBuf> = Process::myself()?
+        .mountinfo()?
+        .into_iter()
+        .map(|mount| mount.mount_point)
+        .filter(|mount_point| mount_point.starts_with(root) && mount_point != root)
+        .collect();
+    children.sort();
+    children.dedup();
+
+    mount_overlayfs(module_roots, &root.to_string_lossy(), root)?;
+
+    for mount_point in children {
+        let relative = mount_point.strip_prefix(root)?;
+        let stock = stock_root.join(relative);
+        if !stock.exists() || !mount_point.exists() {
+            continue;
+        }
+        if let Err(e) = mount_overlay_child(&mount_point, relative, module_roots, &stock) {
+            unmount(root, UnmountFlags::DETACH).ok();
+            return Err(e).with_context(|| format!("restore mount {}", mount_point.display()));
+        }
+    }
+    Ok(())
+}
+
+/// Lower dirs for a partition, the dir listed first wins
+fn module_roots(dirs: impl Iterator<Item = PathBuf>) -> Vec<String> {
+    let mut roots = vec![];
+    for dir in dirs {
+        if !dir.is_dir() || dir.is_symlink() {
+            continue;
+        }
+        let dir = dir.to_string_lossy().to_string();
+        // these separate lowerdir entries and options
+        if dir.contains([':', ',', '\\']) {
+            log::warn!("skip {dir}, the path can not be used by overlayfs");
+            continue;
+        }
+        if let Err(e) = mark_replace_dirs(Path::new(&dir)) {
+            log::warn!("{e:#}");
+        }
+        roots.push(dir);
+    }
+    roots
+}
+
+/// `partitions` must be worked out before the system overlay hides the stock
+/// /system/<partition> symlinks
+fn mount_all(
+    modules: &[PathBuf],
+    partitions: &[(Partition, bool)],
+    mounted: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let system_roots = module_roots(modules.iter().map(|module| module.join("system")));
+    if !system_roots.is_empty() {
+        mount_partition(Path::new("/system"), &system_roots)?;
+        mounted.push(PathBuf::from("/system"));
+    }
+
+    for (partition, separate) in partitions {
+        let name = &partition.name;
+        if !separate {
+            // system/<partition> is already part of the system overlay, only top level dirs remain
+            let roots = module_roots(
+                modules
+                    .iter()
+                    .filter_map(|module| partition::module_partition_dir(module, name)),
+            );
+            let path_of_system = Path::new("/system").join(name);
+            if !roots.is_empty() && path_of_system.is_dir() {
+                mount_partition(&path_of_system, &roots)?;
+                mounted.push(path_of_system);
+            }
+            continue;
+        }
+
+        let roots = module_roots(modules.iter().flat_map(|module| {
+            [
+                partition::module_partition_dir(module, name),
+                Some(module.join("system").join(name)),
+            ]
+            .into_iter()
+            .flatten()
+        }));
+        if roots.is_empty() {
+            continue;
+        }
+        let root = Path::new("/").join(name);
+        mount_partition(&root, &roots)?;
+        mounted.push(root.clone());
+
+        // a module dir at system/<partition> shadows the /system/<partition> symlink
+        let path_of_system = Path::new("/system").join(name);
+        if path_of_system
+            .symlink_metadata()
+            .is_ok_and(|metadata| metadata.is_dir())
+        {
+            mount_bind(&root, &path_of_system).with_context(|| {
+                format!("bind {} to {}", root.display(), path_of_system.display())
+            })?;
+            mounted.push(path_of_system);
+        }
+    }
+    Ok(())
+}
+
+/// Stack the partition dirs of all active modules over the real partitions with overlayfs
+pub fn mount_modules() -> Result<()> {
+    if !overlay_supported() {
+        bail!("overlayfs is not supported by the kernel");
+    }
+    let modules = mountable_modules()?;
+    let partitions = partition::partitions_below(Path::new("/"));
+    let mut mounted = vec![];
+    let result = mount_all(&modules, &partitions, &mut mounted);
+    std::env::set_current_dir("/").ok();
+    if let Err(e) = result {
+        // leave nothing half mounted for the fallback
+        for mount_point in mounted.iter().rev() {
+            if let Err(e) = unmount(mount_point, UnmountFlags::DETACH) {
+                log::error!("failed to unmount {}: {e}", mount_point.display());
+            }
+        }
+        return Err(e);
+    }
+    if mounted.is_empty() {
+        log::info!("no modules to mount, skipping!");
+    }
+    Ok(())
+}
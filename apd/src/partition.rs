@@ -0,0 +1,125 @@
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use procfs::process::Process;
+
+use crate::defs;
+
+/// Partitions which may be mounted on their own, and whether /system/<partition> must be a
+/// symlink for the module's system/<partition> to go to /<partition> instead
+const BUILTIN_PARTITIONS: [(&str, bool); 5] = [
+    ("vendor", true),
+    ("system_ext", true),
+    ("product", true),
+    ("odm", false),
+    ("oem", false),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    pub name: String,
+    require_symlink: bool,
+}
+
+impl Partition {
+    fn new(name: &str, require_symlink: bool) -> Self {
+        Partition {
+            name: name.to_string(),
+            require_symlink,
+        }
+    }
+
+    /// Whether the partition lives at /<name> on this device rather than inside /system
+    pub fn is_separate(&self) -> bool {
+        self.is_separate_in(Path::new("/"))
+    }
+
+    /// Whether the partition lives at <root>/<name> rather than inside <root>/system
+    pub fn is_separate_in(&self, root: &Path) -> bool {
+        let path_of_root = root.join(&self.name);
+        let path_of_system = root.join("system").join(&self.name);
+        path_of_root.is_dir() && (!self.require_symlink || path_of_system.is_symlink())
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name != "system" && !name.starts_with('.') && !name.contains('/')
+}
+
+/// Top-level mounts which look like extra partitions: *_dlkm, OPlus my_* and mi_ext
+fn discover_partitions() -> Vec<String> {
+    let mounts = match Process::myself().and_then(|me| me.mountinfo()) {
+        Ok(mounts) => mounts,
+        Err(e) => {
+            warn!("failed to read mountinfo: {e}");
+            return vec![];
+        }
+    };
+    mounts
+        .into_iter()
+        .filter(|mount| mount.mount_point.parent() == Some(Path::new("/")))
+        .filter_map(|mount| {
+            let name = mount.mount_point.file_name()?.to_str()?.to_string();
+            (name.ends_with("_dlkm") || name.starts_with("my_") || name == "mi_ext").then_some(name)
+        })
+        .collect()
+}
+
+/// Apply `name [symlink]` additions and `!name` removals from the config file
+fn apply_config(partitions: &mut Vec<Partition>, config: &str) {
+    for line in config.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('!') {
+            partitions.retain(|partition| partition.name != name.trim());
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let Some(name) = words.next().filter(|name| is_valid_name(name)) else {
+            warn!("invalid partition in {}: {line}", defs::PARTITIONS_CONFIG);
+            continue;
+        };
+        let require_symlink = words.next() == Some("symlink");
+        partitions.retain(|partition| partition.name != name);
+        partitions.push(Partition::new(name, require_symlink));
+    }
+}
+
+/// Builtin partitions, those discovered from the mounts, then the user's config on top
+pub fn partitions() -> Vec<Partition> {
+    let mut partitions: Vec<Partition> = BUILTIN_PARTITIONS
+        .into_iter()
+        .map(|(name, require_symlink)| Partition::new(name, require_symlink))
+        .collect();
+    for name in discover_partitions() {
+        if !partitions.iter().any(|partition| partition.name == name) {
+            partitions.push(Partition::new(&name, false));
+        }
+    }
+    if let Ok(config) = std::fs::read_to_string(defs::PARTITIONS_CONFIG) {
+        apply_config(&mut partitions, &config);
+    }
+    partitions
+}
+
+/// The partitions and whether each is separate below `root`, to be worked out before any module
+/// is mounted there: a module dir at system/<partition> hides the stock symlink once mounted
+pub fn partitions_below(root: &Path) -> Vec<(Partition, bool)> {
+    partitions()
+        .into_iter()
+        .map(|partition| {
+            let separate = partition.is_separate_in(root);
+            (partition, separate)
+        })
+        .collect()
+}
+
+/// A partition dir shipped at the top of a module; the installer's `<partition> -> system/<partition>`
+/// symlinks are not one
+pub fn module_partition_dir(module: &Path, partition: &str) -> Option<PathBuf> {
+    let dir = module.join(partition);
+    dir.symlink_metadata()
+        .is_ok_and(|metadata| metadata.is_dir())
+        .then_some(dir)
+}
@@ -0,0 +1,316 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use anyhow::{Context, Result, bail, ensure};
+
+/// The policy currently loaded into the kernel
+pub const LOADED_POLICY: &str = "/sys/fs/selinux/policy";
+
+const POLICYDB_MAGIC: u32 = 0xf97cff8c;
+const POLICYDB_STRING: &[u8] = b"SE Linux";
+
+const POLICYDB_VERSION_VALIDATETRANS: u32 = 19;
+const POLICYDB_VERSION_POLCAP: u32 = 22;
+const POLICYDB_VERSION_PERMISSIVE: u32 = 23;
+const POLICYDB_VERSION_BOUNDARY: u32 = 24;
+const POLICYDB_VERSION_NEW_OBJECT_DEFAULTS: u32 = 27;
+const POLICYDB_VERSION_DEFAULT_TYPE: u32 = 28;
+const POLICYDB_VERSION_CONSTRAINT_NAMES: u32 = 29;
+
+const SYM_COMMONS: u32 = 0;
+const SYM_CLASSES: u32 = 1;
+const SYM_ROLES: u32 = 2;
+const SYM_TYPES: u32 = 3;
+
+const CEXPR_NAMES: u32 = 5;
+const TYPEDATUM_PROPERTY_ATTRIBUTE: u32 = 0x2;
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.buf.len());
+        let Some(end) = end else {
+            bail!("truncated policy at offset {}", self.pos);
+        };
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into()?))
+    }
+
+    fn u32s<const N: usize>(&mut self) -> Result<[u32; N]> {
+        let mut values = [0; N];
+        for value in &mut values {
+            *value = self.u32()?;
+        }
+        Ok(values)
+    }
+
+    fn string(&mut self, len: u32) -> Result<String> {
+        let bytes = self.take(len as usize)?;
+        String::from_utf8(bytes.to_vec()).context("invalid identifier in policy")
+    }
+
+    /// Reject element counts which can not possibly fit in the rest of the file
+    fn count(&self, count: u32) -> Result<u32> {
+        ensure!(
+            count as usize <= self.buf.len() - self.pos,
+            "corrupted policy at offset {}",
+            self.pos
+        );
+        Ok(count)
+    }
+
+    fn skip_ebitmap(&mut self) -> Result<()> {
+        let [_mapunit, _highbit, count] = self.u32s()?;
+        // every node is a u32 start bit followed by a u64 map
+        let len = (self.count(count)? as usize) * 12;
+        self.take(len)?;
+        Ok(())
+    }
+
+    fn skip_constraints(&mut self, version: u32, count: u32) -> Result<()> {
+        for _ in 0..self.count(count)? {
+            let [_perms, nexpr] = self.u32s()?;
+            for _ in 0..self.count(nexpr)? {
+                let [expr_type, _attr, _op] = self.u32s()?;
+                if expr_type == CEXPR_NAMES {
+                    self.skip_ebitmap()?;
+                    if version >= POLICYDB_VERSION_CONSTRAINT_NAMES {
+                        // type_set: types, negset and flags
+                        self.skip_ebitmap()?;
+                        self.skip_ebitmap()?;
+                        self.u32()?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn perms(&mut self, count: u32) -> Result<BTreeSet<String>> {
+        let mut perms = BTreeSet::new();
+        for _ in 0..self.count(count)? {
+            let [len, _value] = self.u32s()?;
+            perms.insert(self.string(len)?);
+        }
+        Ok(perms)
+    }
+}
+
+/// Symbols of a compiled (kernel) policy, enough to validate rules against it
+#[derive(Debug, Default)]
+pub struct PolicyDb {
+    pub version: u32,
+    types: BTreeSet<String>,
+    attributes: BTreeSet<String>,
+    classes: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl PolicyDb {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let buf = std::fs::read(path)
+            .with_context(|| format!("Failed to read policy {}", path.display()))?;
+        Self::parse(&buf).with_context(|| format!("Failed to parse policy {}", path.display()))
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        let mut reader = Reader { buf, pos: 0 };
+        let [magic, len] = reader.u32s()?;
+        ensure!(
+            magic == POLICYDB_MAGIC,
+            "not a binary policy, magic {magic:#x}"
+        );
+        ensure!(
+            reader.take(len as usize)? == POLICYDB_STRING,
+            "not an SELinux policy"
+        );
+        let [version, _config, sym_num, _ocon_num] = reader.u32s()?;
+        ensure!(
+            sym_num > SYM_TYPES,
+            "policy has only {sym_num} symbol tables"
+        );
+
+        if version >= POLICYDB_VERSION_POLCAP {
+            reader.skip_ebitmap()?;
+        }
+        if version >= POLICYDB_VERSION_PERMISSIVE {
+            reader.skip_ebitmap()?;
+        }
+
+        let mut db = PolicyDb {
+            version,
+            ..Default::default()
+        };
+        let mut commons = BTreeMap::new();
+        // the symbol tables come in a fixed order, everything after types is not needed
+        for sym in SYM_COMMONS..=SYM_TYPES {
+            let [_nprim, nel] = reader.u32s()?;
+            for _ in 0..reader.count(nel)? {
+                match sym {
+                    SYM_COMMONS => {
+                        let [len, _value, _nprim, nel] = reader.u32s()?;
+                        let name = reader.string(len)?;
+                        let perms = reader.perms(nel)?;
+                        commons.insert(name, perms);
+                    }
+                    SYM_CLASSES => {
+                        let [len, common_len, _value, _nprim, nel, ncons] = reader.u32s()?;
+                        let name = reader.string(len)?;
+                        let mut perms = BTreeSet::new();
+                        if common_len > 0 {
+                            let common = reader.string(common_len)?;
+                            let Some(inherited) = commons.get(&common) else {
+                                bail!("class {name} inherits unknown common {common}");
+                            };
+                            perms.extend(inherited.iter().cloned());
+                        }
+                        perms.extend(reader.perms(nel)?);
+                        reader.skip_constraints(version, ncons)?;
+                        if version >= POLICYDB_VERSION_VALIDATETRANS {
+                            let ncons = reader.u32()?;
+                            reader.skip_constraints(version, ncons)?;
+                        }
+                        if version >= POLICYDB_VERSION_NEW_OBJECT_DEFAULTS {
+                            reader.u32s::<3>()?;
+                        }
+                        if version >= POLICYDB_VERSION_DEFAULT_TYPE {
+                            reader.u32()?;
+                        }
+                        db.classes.insert(name, perms);
+                    }
+                    SYM_ROLES => {
+                        let len = if version >= POLICYDB_VERSION_BOUNDARY {
+                            reader.u32s::<3>()?[0]
+                        } else {
+                            reader.u32s::<2>()?[0]
+                        };
+                        reader.string(len)?;
+                        // dominates and types
+                        reader.skip_ebitmap()?;
+                        reader.skip_ebitmap()?;
+                    }
+                    _ => {
+                        let (len, properties) = if version >= POLICYDB_VERSION_BOUNDARY {
+                            let [len, _value, properties, _bounds] = reader.u32s()?;
+                            (len, properties)
+                        } else {
+                            let [len, _value, _primary] = reader.u32s()?;
+                            (len, 0)
+                        };
+                        let name = reader.string(len)?;
+                        if properties & TYPEDATUM_PROPERTY_ATTRIBUTE != 0 {
+                            db.attributes.insert(name);
+                        } else {
+                            db.types.insert(name);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(db)
+    }
+
+    pub fn types(&self) -> impl Iterator<Item = &str> {
+        self.types.iter().map(String::as_str)
+    }
+
+    pub fn attributes(&self) -> impl Iterator<Item = &str> {
+        self.attributes.iter().map(String::as_str)
+    }
+
+    pub fn classes(&self) -> impl Iterator<Item = &str> {
+        self.classes.keys().map(String::as_str)
+    }
+
+    pub fn is_type(&self, name: &str) -> bool {
+        self.types.contains(name)
+    }
+
+    pub fn is_attribute(&self, name: &str) -> bool {
+        self.attributes.contains(name)
+    }
+
+    pub fn class_perms(&self, class: &str) -> Option<&BTreeSet<String>> {
+        self.classes.get(class)
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "policy version {}: {} types, {} attributes, {} classes",
+            self.version,
+            self.types.len(),
+            self.attributes.len(),
+            self.classes.len()
+        )
+    }
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitute = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Up to three candidates close enough to `name` to be a likely typo or abbreviation
+pub fn suggest<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+    let limit = (name.chars().count() / 3).max(1);
+    let mut matches: Vec<(usize, &str)> = candidates
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|&(distance, candidate)| distance <= limit || candidate.starts_with(name))
+        .collect();
+    matches.sort();
+    matches.into_iter().take(3).map(|(_, name)| name).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Symbol tables of a tiny version 30 policy: classes file, dir and process,
+    /// types init, shell, system_file and app_data_file, attributes domain and file_type
+    const FIXTURE: &[u8] = include_bytes!("../tests/fixtures/policy.30");
+
+    #[test]
+    fn parse_fixture() {
+        let db = PolicyDb::parse(FIXTURE).unwrap();
+        assert_eq!(db.version, 30);
+        assert!(db.is_type("shell"));
+        assert!(db.is_attribute("domain"));
+        assert!(!db.is_type("domain"));
+        let file = db.class_perms("file").unwrap();
+        // inherited from the file common, and the class's own
+        assert!(file.contains("read"));
+        assert!(file.contains("execute_no_trans"));
+        assert!(!db.class_perms("process").unwrap().contains("read"));
+    }
+
+    #[test]
+    fn reject_truncated() {
+        assert!(PolicyDb::parse(&FIXTURE[..FIXTURE.len() / 2]).is_err());
+        assert!(PolicyDb::parse(b"not a policy").is_err());
+    }
+}
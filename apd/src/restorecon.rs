@@ -1,13 +1,23 @@
-use std::path::Path;
+use std::{
+    collections::BTreeMap,
+    fs,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+    thread,
+};
 
-use anyhow::Result;
 #[cfg(any(target_os = "linux", target_os = "android"))]
-use anyhow::{Context, Ok};
+use anyhow::Ok;
+use anyhow::{Context, Result, ensure};
 #[cfg(any(target_os = "linux", target_os = "android"))]
 use extattr::{Flags as XattrFlags, lsetxattr};
 use jwalk::{Parallelism::Serial, WalkDir};
+use sha2::{Digest, Sha256};
 
-use crate::defs;
+use crate::{
+    defs,
+    file_contexts::{DEVICE_FILE_CONTEXTS, ModuleLabeler},
+};
 
 pub const SYSTEM_CON: &str = "u:object_r:system_file:s0";
 pub const ADB_CON: &str = "u:object_r:adb_data_file:s0";
@@ -62,21 +72,207 @@ pub fn restore_syscon<P: AsRef<Path>>(dir: P) -> Result<()> {
     Ok(())
 }
 
-fn restore_syscon_if_unlabeled<P: AsRef<Path>>(dir: P) -> Result<()> {
-    for dir_entry in WalkDir::new(dir).parallelism(Serial) {
-        if let Some(path) = dir_entry.ok().map(|dir_entry| dir_entry.path()) {
-            if let Result::Ok(con) = lgetfilecon(&path) {
-                if con == UNLABEL_CON || con.is_empty() {
-                    lsetfilecon(&path, SYSTEM_CON)?;
-                }
+/// A file whose label differs from the one the boot pass gives it
+pub struct LabelIssue {
+    pub path: PathBuf,
+    pub current: Option<String>,
+    pub expected: String,
+}
+
+/// Compare a module's labels with the expected ones, relabeling the differences when `fix` is set
+///
+/// Modules with a file_contexts are held to it. The others should keep the system_file
+/// install gives system/, but only unlabeled files are fixed up, customize.sh may have
+/// labeled the rest on purpose
+fn audit_module(module_dir: &Path, fix: bool) -> Result<Vec<LabelIssue>> {
+    let mut issues = vec![];
+    let mut check = |path: &Path, expected: &str| -> Result<()> {
+        let current = lgetfilecon(path).ok();
+        if current.as_deref() == Some(expected) {
+            return Ok(());
+        }
+        if fix {
+            lsetfilecon(path, expected)?;
+        }
+        issues.push(LabelIssue {
+            path: path.to_path_buf(),
+            current,
+            expected: expected.to_string(),
+        });
+        Ok(())
+    };
+    if let Some(labeler) = ModuleLabeler::new(module_dir) {
+        labeler.walk(module_dir, check)?;
+    } else {
+        let system_dir = module_dir.join("system");
+        for dir_entry in WalkDir::new(module_dir)
+            .parallelism(Serial)
+            .into_iter()
+            .flatten()
+        {
+            let path = dir_entry.path();
+            let unlabeled =
+                lgetfilecon(&path).is_ok_and(|con| con == UNLABEL_CON || con.is_empty());
+            if unlabeled || (!fix && path.starts_with(&system_dir)) {
+                check(&path, SYSTEM_CON)?;
             }
         }
     }
-    Ok(())
+    Ok(issues)
+}
+
+fn module_dirs() -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(defs::MODULE_DIR) {
+        Result::Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut dirs: Vec<PathBuf> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect();
+    dirs.sort();
+    Ok(dirs)
+}
+
+fn hash_metadata(hasher: &mut Sha256, path: &Path) {
+    hasher.update(path.as_os_str().as_bytes());
+    hasher.update([0]);
+    match fs::symlink_metadata(path) {
+        Result::Ok(metadata) => {
+            hasher.update(metadata.mtime().to_le_bytes());
+            hasher.update(metadata.mtime_nsec().to_le_bytes());
+            hasher.update(metadata.size().to_le_bytes());
+        }
+        Err(_) => hasher.update([0]),
+    }
+}
+
+/// sha256 of every path, mtime and size in the module, plus the device file_contexts, so the
+/// manifest stays valid across apd builds
+fn tree_digest(module_dir: &Path) -> String {
+    let mut hasher = Sha256::new();
+    for path in DEVICE_FILE_CONTEXTS {
+        hash_metadata(&mut hasher, Path::new(path));
+    }
+    let walker = WalkDir::new(module_dir)
+        .parallelism(Serial)
+        .sort(true)
+        .into_iter()
+        .flatten();
+    for dir_entry in walker {
+        hash_metadata(&mut hasher, &dir_entry.path());
+    }
+    hex::encode(hasher.finalize())
+}
+
+fn read_manifest() -> BTreeMap<String, String> {
+    fs::read_to_string(defs::RESTORECON_MANIFEST)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn module_name(module_dir: &Path) -> String {
+    module_dir
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Relabel one module unless its tree matches the manifest, returns its new digest
+fn restorecon_module(module_dir: &Path, known: Option<&String>) -> Result<String> {
+    let digest = tree_digest(module_dir);
+    if known == Some(&digest) {
+        log::debug!("{} unchanged, skip restorecon", module_dir.display());
+        return Ok(digest);
+    }
+    let issues = audit_module(module_dir, true)?;
+    if !issues.is_empty() {
+        log::info!(
+            "relabeled {} files of {}",
+            issues.len(),
+            module_dir.display()
+        );
+    }
+    Ok(digest)
 }
 
 pub fn restorecon() -> Result<()> {
     lsetfilecon(defs::DAEMON_PATH, ADB_CON)?;
-    restore_syscon_if_unlabeled(defs::MODULE_DIR)?;
+
+    let manifest = read_manifest();
+    let modules = module_dirs()?;
+    let workers = thread::available_parallelism().map_or(4, |n| n.get());
+    let chunk_size = modules.len().div_ceil(workers).max(1);
+    let (results, panicked) = thread::scope(|scope| {
+        let handles: Vec<_> = modules
+            .chunks(chunk_size)
+            .map(|chunk| {
+                let manifest = &manifest;
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|module_dir| {
+                            let name = module_name(module_dir);
+                            let known = manifest.get(&name);
+                            (name, restorecon_module(module_dir, known))
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut results: Vec<(String, Result<String>)> = vec![];
+        let mut panicked = 0;
+        for handle in handles {
+            match handle.join() {
+                Result::Ok(chunk) => results.extend(chunk),
+                Err(_) => panicked += 1,
+            }
+        }
+        (results, panicked)
+    });
+
+    let mut updated = BTreeMap::new();
+    for (name, result) in results {
+        match result {
+            Result::Ok(digest) => {
+                updated.insert(name, digest);
+            }
+            Err(e) => log::warn!("restorecon {name} failed: {e:#}"),
+        }
+    }
+    fs::write(defs::RESTORECON_MANIFEST, serde_json::to_string(&updated)?)
+        .context("Failed to write restorecon manifest")?;
+    ensure!(
+        panicked == 0,
+        "{panicked} restorecon workers panicked, their modules were not relabeled"
+    );
+    Ok(())
+}
+
+/// Report mislabeled and unlabeled module files without touching them
+pub fn check() -> Result<()> {
+    let mut total = 0;
+    for module_dir in module_dirs()? {
+        let issues = audit_module(&module_dir, false)?;
+        if issues.is_empty() {
+            continue;
+        }
+        total += issues.len();
+        println!("{}: {} files", module_name(&module_dir), issues.len());
+        for issue in issues {
+            println!(
+                "  {} {} -> {}",
+                issue.path.display(),
+                issue.current.as_deref().unwrap_or("<none>"),
+                issue.expected
+            );
+        }
+    }
+    if total == 0 {
+        println!("All module files are labeled as expected");
+    }
     Ok(())
 }
@@ -1,7 +1,15 @@
-use std::{ffi, path::Path, vec};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::{self, CString},
+    fmt,
+    path::{Path, PathBuf},
+    process::Command,
+    vec,
+};
 
-use anyhow::{Result, bail};
+use anyhow::{Context, Result, bail, ensure};
 use derive_new::new;
+use log::{info, warn};
 use nom::{
     AsChar, IResult, Parser,
     branch::alt,
@@ -9,9 +17,18 @@ use nom::{
     character::complete::{space0, space1},
     combinator::map,
 };
+use serde::Serialize;
+
+use crate::{
+    assets, defs,
+    policydb::{self, PolicyDb},
+    supercall,
+};
 
 type SeObject<'a> = Vec<&'a str>;
 
+const BRACE_LIST_MAX_LEN: usize = 100;
+
 fn is_sepolicy_char(c: char) -> bool {
     c.is_alphanum() || c == '_' || c == '-'
 }
@@ -23,7 +40,9 @@ fn parse_single_word(input: &str) -> IResult<&str, &str> {
 fn parse_bracket_objs(input: &str) -> IResult<&str, SeObject<'_>> {
     let (input, (_, words, _)) = (
         tag("{"),
-        take_while_m_n(1, 100, |c: char| is_sepolicy_char(c) || c.is_whitespace()),
+        take_while_m_n(1, BRACE_LIST_MAX_LEN, |c: char| {
+            is_sepolicy_char(c) || c.is_whitespace()
+        }),
         tag("}"),
     )
         .parse(input)?;
@@ -59,7 +78,7 @@ trait SeObjectParser<'a> {
         Self: Sized;
 }
 
-#[derive(Debug, PartialEq, Eq, new)]
+#[derive(Debug, PartialEq, Eq, Serialize, new)]
 struct NormalPerm<'a> {
     op: &'a str,
     source: SeObject<'a>,
@@ -68,7 +87,7 @@ struct NormalPerm<'a> {
     perm: SeObject<'a>,
 }
 
-#[derive(Debug, PartialEq, Eq, new)]
+#[derive(Debug, PartialEq, Eq, Serialize, new)]
 struct XPerm<'a> {
     op: &'a str,
     source: SeObject<'a>,
@@ -78,30 +97,30 @@ struct XPerm<'a> {
     perm_set: &'a str,
 }
 
-#[derive(Debug, PartialEq, Eq, new)]
+#[derive(Debug, PartialEq, Eq, Serialize, new)]
 struct TypeState<'a> {
     op: &'a str,
     stype: SeObject<'a>,
 }
 
-#[derive(Debug, PartialEq, Eq, new)]
+#[derive(Debug, PartialEq, Eq, Serialize, new)]
 struct TypeAttr<'a> {
     stype: SeObject<'a>,
     sattr: SeObject<'a>,
 }
 
-#[derive(Debug, PartialEq, Eq, new)]
+#[derive(Debug, PartialEq, Eq, Serialize, new)]
 struct Type<'a> {
     name: &'a str,
     attrs: SeObject<'a>,
 }
 
-#[derive(Debug, PartialEq, Eq, new)]
+#[derive(Debug, PartialEq, Eq, Serialize, new)]
 struct Attr<'a> {
     name: &'a str,
 }
 
-#[derive(Debug, PartialEq, Eq, new)]
+#[derive(Debug, PartialEq, Eq, Serialize, new)]
 struct TypeTransition<'a> {
     source: &'a str,
     target: &'a str,
@@ -110,7 +129,7 @@ struct TypeTransition<'a> {
     object_name: Option<&'a str>,
 }
 
-#[derive(Debug, PartialEq, Eq, new)]
+#[derive(Debug, PartialEq, Eq, Serialize, new)]
 struct TypeChange<'a> {
     op: &'a str,
     source: &'a str,
@@ -119,14 +138,15 @@ struct TypeChange<'a> {
     default_type: &'a str,
 }
 
-#[derive(Debug, PartialEq, Eq, new)]
+#[derive(Debug, PartialEq, Eq, Serialize, new)]
 struct GenFsCon<'a> {
     fs_name: &'a str,
     partial_path: &'a str,
     fs_context: &'a str,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
 enum PolicyStatement<'a> {
     // "allow *source_type *target_type *class *perm_set"
     // "deny *source_type *target_type *class *perm_set"
@@ -173,7 +193,8 @@ impl<'a> SeObjectParser<'a> for NormalPerm<'a> {
         ))
         .parse(input)?;
 
-        let (input, _) = space0(input)?;
+        // a bare prefix such as `allow` of `allowxperm` is not a keyword
+        let (input, _) = space1(input)?;
         let (input, source) = parse_seobj(input)?;
         let (input, _) = space0(input)?;
         let (input, target) = parse_seobj(input)?;
@@ -194,7 +215,8 @@ impl<'a> SeObjectParser<'a> for XPerm<'a> {
         ))
         .parse(input)?;
 
-        let (input, _) = space0(input)?;
+        // a bare prefix such as `allow` of `allowxperm` is not a keyword
+        let (input, _) = space1(input)?;
         let (input, source) = parse_seobj(input)?;
         let (input, _) = space0(input)?;
         let (input, target) = parse_seobj(input)?;
@@ -345,21 +367,146 @@ impl<'a> PolicyStatement<'a> {
     }
 }
 
+/// Canonical form of an object list: a bare word, `*`, or `{ a b c }`
+struct SeObjectDisplay<'a, 'b>(&'b [&'a str]);
+
+impl fmt::Display for SeObjectDisplay<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            [single] => write!(f, "{single}"),
+            objs => {
+                let joined = objs.join(" ");
+                // the padding must not push the list over what the parser accepts
+                if joined.len() + 2 <= BRACE_LIST_MAX_LEN {
+                    write!(f, "{{ {joined} }}")
+                } else {
+                    write!(f, "{{{joined}}}")
+                }
+            }
+        }
+    }
+}
+
+/// Render the statement in canonical form, parsing it back gives the same statement
+impl fmt::Display for PolicyStatement<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let obj = SeObjectDisplay;
+        match self {
+            PolicyStatement::NormalPerm(p) => write!(
+                f,
+                "{} {} {} {} {}",
+                p.op,
+                obj(&p.source),
+                obj(&p.target),
+                obj(&p.class),
+                obj(&p.perm)
+            ),
+            PolicyStatement::XPerm(p) => write!(
+                f,
+                "{} {} {} {} {} {}",
+                p.op,
+                obj(&p.source),
+                obj(&p.target),
+                obj(&p.class),
+                p.operation,
+                p.perm_set
+            ),
+            PolicyStatement::TypeState(t) => write!(f, "{} {}", t.op, obj(&t.stype)),
+            PolicyStatement::Type(t) => write!(f, "type {} {}", t.name, obj(&t.attrs)),
+            PolicyStatement::TypeAttr(t) => {
+                write!(f, "typeattribute {} {}", obj(&t.stype), obj(&t.sattr))
+            }
+            PolicyStatement::Attr(a) => write!(f, "attribute {}", a.name),
+            PolicyStatement::TypeTransition(t) => {
+                write!(
+                    f,
+                    "type_transition {} {} {} {}",
+                    t.source, t.target, t.class, t.default_type
+                )?;
+                if let Some(name) = t.object_name {
+                    write!(f, " {name}")?;
+                }
+                Ok(())
+            }
+            PolicyStatement::TypeChange(t) => write!(
+                f,
+                "{} {} {} {} {}",
+                t.op, t.source, t.target, t.class, t.default_type
+            ),
+            PolicyStatement::GenFsCon(g) => write!(
+                f,
+                "genfscon {} {} {}",
+                g.fs_name, g.partial_path, g.fs_context
+            ),
+        }
+    }
+}
+
+/// One statement of a rule file with its position, both newlines and `;` end a statement
+/// and `#` comments out the rest of it
+struct Segment<'a> {
+    text: &'a str,
+    line: usize,
+    offset: usize,
+    line_text: &'a str,
+}
+
+impl Segment<'_> {
+    /// 1-based column of a byte offset inside this statement
+    fn column(&self, offset: usize) -> usize {
+        self.line_text[..self.offset + offset].chars().count() + 1
+    }
+}
+
+fn strip_comment(text: &str) -> &str {
+    text.split_once('#').map_or(text, |(code, _)| code)
+}
+
+fn split_statements(input: &str) -> Vec<Segment<'_>> {
+    let mut segments = vec![];
+    for (index, line_text) in input.split('\n').enumerate() {
+        let mut offset = 0;
+        for part in line_text.split(';') {
+            let trimmed = part.trim_start();
+            let start = offset + part.len() - trimmed.len();
+            offset += part.len() + 1;
+            let text = strip_comment(trimmed).trim_end();
+            if text.is_empty() {
+                continue;
+            }
+            segments.push(Segment {
+                text,
+                line: index + 1,
+                offset: start,
+                line_text,
+            });
+        }
+    }
+    segments
+}
+
+fn parse_statement(input: &str) -> Option<PolicyStatement<'_>> {
+    match PolicyStatement::parse(input) {
+        Ok(("", statement)) => Some(statement),
+        _ => None,
+    }
+}
+
 fn parse_sepolicy<'a, 'b>(input: &'b str, strict: bool) -> Result<Vec<PolicyStatement<'a>>>
 where
     'b: 'a,
 {
     let mut statements = vec![];
 
-    for line in input.split(['\n', ';']) {
-        let trimmed_line = line.trim();
-        if trimmed_line.is_empty() || trimmed_line.starts_with('#') {
-            continue;
-        }
-        if let Ok((_, statement)) = PolicyStatement::parse(trimmed_line) {
+    for segment in split_statements(input) {
+        if let Some(statement) = parse_statement(segment.text) {
             statements.push(statement);
         } else if strict {
-            bail!("Failed to parse policy statement: {}", line)
+            bail!(
+                "Failed to parse policy statement at line {}: {}",
+                segment.line,
+                segment.text
+            )
         }
     }
     Ok(statements)
@@ -380,15 +527,29 @@ const CMD_GENFSCON: u32 = 9;
 #[derive(Debug, Default)]
 enum PolicyObject {
     All, // for "*", stand for all objects, and is NULL in ffi
-    One(#[allow(dead_code)] [u8; SEPOLICY_MAX_LEN]), // Field is used in to_c_ptr function
+    One([u8; SEPOLICY_MAX_LEN]),
     #[default]
     None,
 }
 
+impl PolicyObject {
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            PolicyObject::All => Some("*"),
+            PolicyObject::One(buf) => {
+                let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+                std::str::from_utf8(&buf[..len]).ok()
+            }
+            PolicyObject::None => None,
+        }
+    }
+}
+
 impl TryFrom<&str> for PolicyObject {
     type Error = anyhow::Error;
     fn try_from(s: &str) -> Result<Self> {
-        anyhow::ensure!(s.len() <= SEPOLICY_MAX_LEN, "policy object too long");
+        // keep room for the trailing NUL, the kernel reads these as C strings
+        anyhow::ensure!(s.len() < SEPOLICY_MAX_LEN, "policy object too long: {s}");
         if s == "*" {
             return Ok(PolicyObject::All);
         }
@@ -407,20 +568,120 @@ impl TryFrom<&str> for PolicyObject {
 struct AtomicStatement {
     cmd: u32,
     subcmd: u32,
-    #[allow(dead_code)]
-    sepol1: PolicyObject, // Used in FfiPolicy conversion
-    #[allow(dead_code)]
-    sepol2: PolicyObject, // Used in FfiPolicy conversion
-    #[allow(dead_code)]
-    sepol3: PolicyObject, // Used in FfiPolicy conversion
-    #[allow(dead_code)]
-    sepol4: PolicyObject, // Used in FfiPolicy conversion
-    #[allow(dead_code)]
-    sepol5: PolicyObject, // Used in FfiPolicy conversion
-    #[allow(dead_code)]
-    sepol6: PolicyObject, // Used in FfiPolicy conversion
-    #[allow(dead_code)]
-    sepol7: PolicyObject, // Used in FfiPolicy conversion
+    sepol1: PolicyObject,
+    sepol2: PolicyObject,
+    sepol3: PolicyObject,
+    sepol4: PolicyObject,
+    sepol5: PolicyObject,
+    sepol6: PolicyObject,
+    sepol7: PolicyObject,
+}
+
+impl AtomicStatement {
+    fn keyword(&self) -> &'static str {
+        match (self.cmd, self.subcmd) {
+            (CMD_NORMAL_PERM, 1) => "allow",
+            (CMD_NORMAL_PERM, 2) => "deny",
+            (CMD_NORMAL_PERM, 3) => "auditallow",
+            (CMD_NORMAL_PERM, 4) => "dontaudit",
+            (CMD_XPERM, 1) => "allowxperm",
+            (CMD_XPERM, 2) => "auditallowxperm",
+            (CMD_XPERM, 3) => "dontauditxperm",
+            (CMD_TYPE_STATE, 1) => "permissive",
+            (CMD_TYPE_STATE, 2) => "enforce",
+            (CMD_TYPE, _) => "type",
+            (CMD_TYPE_ATTR, _) => "typeattribute",
+            (CMD_ATTR, _) => "attribute",
+            (CMD_TYPE_TRANSITION, _) => "type_transition",
+            (CMD_TYPE_CHANGE, 1) => "type_change",
+            (CMD_TYPE_CHANGE, 2) => "type_member",
+            (CMD_GENFSCON, _) => "genfscon",
+            _ => "unknown",
+        }
+    }
+
+    fn objects(&self) -> impl Iterator<Item = &str> {
+        [
+            &self.sepol1,
+            &self.sepol2,
+            &self.sepol3,
+            &self.sepol4,
+            &self.sepol5,
+            &self.sepol6,
+            &self.sepol7,
+        ]
+        .into_iter()
+        .map_while(PolicyObject::as_str)
+    }
+}
+
+/// Render the statement in magiskpolicy syntax, e.g. `allow domain1 domain2 file1 read`
+impl fmt::Display for AtomicStatement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.keyword())?;
+        for obj in self.objects() {
+            write!(f, " {obj}")?;
+        }
+        Ok(())
+    }
+}
+
+/// `u:object_r:type:s0` as a CIL context `(u object_r type ((s0) (s0)))`
+fn context_to_cil(context: &str) -> Option<String> {
+    let [user, role, stype, level] = context.split(':').collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    Some(format!("({user} {role} {stype} (({level}) ({level})))"))
+}
+
+impl AtomicStatement {
+    /// Render the statement as CIL, None if CIL has no equivalent (deny, enforce, `*` types)
+    fn to_cil(&self) -> Option<Vec<String>> {
+        let objs: Vec<&str> = self.objects().collect();
+        let keyword = self.keyword();
+        // only permissions have a wildcard in CIL
+        let wildcard = objs.iter().position(|&obj| obj == "*");
+        if wildcard.is_some_and(|pos| self.cmd != CMD_NORMAL_PERM || pos != 3) {
+            return None;
+        }
+        let lines = match (keyword, &objs[..]) {
+            ("allow" | "auditallow" | "dontaudit", [s, t, c, p]) => {
+                let p = if *p == "*" { "all" } else { p };
+                vec![format!("({keyword} {s} {t} ({c} ({p})))")]
+            }
+            ("allowxperm" | "auditallowxperm" | "dontauditxperm", [s, t, c, op, set]) => {
+                let keyword = keyword.trim_end_matches("perm");
+                let set = match set.split_once('-') {
+                    Some((low, high)) => format!("range {low} {high}"),
+                    None => set.to_string(),
+                };
+                vec![format!("({keyword} {s} {t} ({op} {c} ({set})))")]
+            }
+            ("permissive", [t]) => vec![format!("(typepermissive {t})")],
+            ("type", [name, attr]) => vec![
+                format!("(type {name})"),
+                format!("(typeattributeset {attr} ({name}))"),
+            ],
+            ("typeattribute", [t, attr]) => vec![format!("(typeattributeset {attr} ({t}))")],
+            ("attribute", [attr]) => vec![format!("(typeattribute {attr})")],
+            ("type_transition", [s, t, c, d]) => vec![format!("(typetransition {s} {t} {c} {d})")],
+            ("type_transition", [s, t, c, d, name]) => {
+                vec![format!("(typetransition {s} {t} {c} \"{name}\" {d})")]
+            }
+            ("type_change" | "type_member", [s, t, c, d]) => {
+                let keyword = keyword.replace('_', "");
+                vec![format!("({keyword} {s} {t} {c} {d})")]
+            }
+            ("genfscon", [fs, path, context]) => {
+                vec![format!(
+                    "(genfscon {fs} {path} {})",
+                    context_to_cil(context)?
+                )]
+            }
+            _ => return None,
+        };
+        Some(lines)
+    }
 }
 
 impl<'a> TryFrom<&'a NormalPerm<'a>> for Vec<AtomicStatement> {
@@ -494,7 +755,7 @@ impl<'a> TryFrom<&'a TypeState<'a>> for Vec<AtomicStatement> {
         let mut result = vec![];
         let subcmd = match perm.op {
             "permissive" => 1,
-            "enforcing" => 2,
+            "enforce" => 2,
             _ => 0,
         };
         for &t in &perm.stype {
@@ -664,7 +925,6 @@ impl<'a> TryFrom<&'a PolicyStatement<'a>> for Vec<AtomicStatement> {
 
 #[derive(Debug)]
 #[repr(C)]
-#[allow(dead_code)] // Used for FFI interface
 struct FfiPolicy {
     cmd: u32,
     subcmd: u32,
@@ -677,7 +937,6 @@ struct FfiPolicy {
     sepol7: *const ffi::c_char,
 }
 
-#[allow(dead_code)] // Used in FfiPolicy conversion
 fn to_c_ptr(pol: &PolicyObject) -> *const ffi::c_char {
     match pol {
         PolicyObject::None | PolicyObject::All => std::ptr::null(),
@@ -685,8 +944,9 @@ fn to_c_ptr(pol: &PolicyObject) -> *const ffi::c_char {
     }
 }
 
-impl From<AtomicStatement> for FfiPolicy {
-    fn from(policy: AtomicStatement) -> FfiPolicy {
+// the pointers borrow from the statement, so it must outlive the FfiPolicy
+impl From<&AtomicStatement> for FfiPolicy {
+    fn from(policy: &AtomicStatement) -> FfiPolicy {
         FfiPolicy {
             cmd: policy.cmd,
             subcmd: policy.subcmd,
@@ -701,13 +961,1167 @@ impl From<AtomicStatement> for FfiPolicy {
     }
 }
 
+////////////////////////////////////////////////////////////////
+///  diagnostics, report every problem with its position
+///////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Diagnostic {
+    severity: Severity,
+    line: usize,
+    column: usize,
+    kind: Option<&'static str>,
+    message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}: ", self.line, self.column, self.severity)?;
+        if let Some(kind) = self.kind {
+            write!(f, "[{kind}] ")?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// The fields following a statement keyword, mirrors the nom parsers above
+#[derive(Clone, Copy)]
+enum Field {
+    Obj(&'static str),
+    ObjNoStar(&'static str),
+    Word(&'static str),
+    OptObjNoStar(&'static str),
+    OptWord(&'static str),
+}
+
+impl Field {
+    fn optional(self) -> bool {
+        matches!(self, Field::OptObjNoStar(_) | Field::OptWord(_))
+    }
+
+    fn describe(self) -> String {
+        match self {
+            Field::Obj(name) => format!("{name} (name, `{{...}}` or `*`)"),
+            Field::ObjNoStar(name) | Field::OptObjNoStar(name) => {
+                format!("{name} (name or `{{...}}`)")
+            }
+            Field::Word(name) | Field::OptWord(name) => name.to_string(),
+        }
+    }
+}
+
+fn grammar(keyword: &str) -> Option<(&'static str, &'static [Field])> {
+    use Field::*;
+    Some(match keyword {
+        "allow" | "deny" | "auditallow" | "dontaudit" => (
+            "permission rule",
+            &[
+                Obj("source type"),
+                Obj("target type"),
+                Obj("class"),
+                Obj("permission"),
+            ],
+        ),
+        "allowxperm" | "auditallowxperm" | "dontauditxperm" => (
+            "extended permission rule",
+            &[
+                Obj("source type"),
+                Obj("target type"),
+                Obj("class"),
+                Word("operation"),
+                Word("xperm set"),
+            ],
+        ),
+        "permissive" | "enforce" => ("type state", &[ObjNoStar("type")]),
+        "type" => (
+            "type declaration",
+            &[Word("type name"), OptObjNoStar("attribute")],
+        ),
+        "typeattribute" | "attradd" => (
+            "type attribute",
+            &[ObjNoStar("type"), ObjNoStar("attribute")],
+        ),
+        "attribute" => ("attribute declaration", &[Word("attribute name")]),
+        "type_transition" | "name_transition" => (
+            "type transition",
+            &[
+                Word("source type"),
+                Word("target type"),
+                Word("class"),
+                Word("default type"),
+                OptWord("object name"),
+            ],
+        ),
+        "type_change" | "type_member" => (
+            "type change",
+            &[
+                Word("source type"),
+                Word("target type"),
+                Word("class"),
+                Word("default type"),
+            ],
+        ),
+        "genfscon" => (
+            "genfscon",
+            &[
+                Word("filesystem name"),
+                Word("partial path"),
+                Word("file context"),
+            ],
+        ),
+        _ => return None,
+    })
+}
+
+fn token(input: &str) -> &str {
+    let end = input.find(char::is_whitespace).unwrap_or(input.len());
+    &input[..end]
+}
+
+/// Length of the field at the start of `input`, or the offset and reason it failed
+fn scan_field(input: &str, field: Field) -> Result<usize, (usize, String)> {
+    let word_len = input.find(|c| !is_sepolicy_char(c)).unwrap_or(input.len());
+    if word_len > 0 {
+        return Ok(word_len);
+    }
+    let (allow_list, allow_star) = match field {
+        Field::Obj(_) => (true, true),
+        Field::ObjNoStar(_) | Field::OptObjNoStar(_) => (true, false),
+        Field::Word(_) | Field::OptWord(_) => (false, false),
+    };
+    if allow_star && input.starts_with('*') {
+        return Ok(1);
+    }
+    if allow_list && input.starts_with('{') {
+        let Some(end) = input.find('}') else {
+            return Err((
+                input.len(),
+                "unterminated brace list, expected `}`".to_string(),
+            ));
+        };
+        let body = &input[1..end];
+        if let Some(bad) = body.find(|c: char| !is_sepolicy_char(c) && !c.is_whitespace()) {
+            return Err((
+                1 + bad,
+                format!("unexpected `{}` in brace list", token(&body[bad..])),
+            ));
+        }
+        if body.len() > BRACE_LIST_MAX_LEN {
+            return Err((
+                0,
+                format!(
+                    "brace list is {} characters long, only {BRACE_LIST_MAX_LEN} are supported",
+                    body.len()
+                ),
+            ));
+        }
+        return Ok(end + 1);
+    }
+    Err((
+        0,
+        format!("expected {}, found `{}`", field.describe(), token(input)),
+    ))
+}
+
+/// Walk a statement field by field and locate the first syntax error
+fn locate_error(text: &str, keyword_len: usize, fields: &[Field]) -> Option<(usize, String)> {
+    let mut pos = keyword_len;
+    for &field in fields {
+        let rest = &text[pos..];
+        let trimmed = rest.trim_start();
+        let separated = trimmed.len() < rest.len();
+        pos += rest.len() - trimmed.len();
+        if trimmed.is_empty() {
+            if field.optional() {
+                return None;
+            }
+            return Some((
+                pos,
+                format!("expected {}, found end of statement", field.describe()),
+            ));
+        }
+        if !separated {
+            return Some((
+                pos,
+                format!("expected whitespace before {}", field.describe()),
+            ));
+        }
+        match scan_field(trimmed, field) {
+            Ok(len) => pos += len,
+            Err((offset, message)) => return Some((pos + offset, message)),
+        }
+    }
+    let rest = text[pos..].trim_start();
+    if rest.is_empty() {
+        return None;
+    }
+    Some((
+        text.len() - rest.len(),
+        format!("unexpected `{}` after end of statement", token(rest)),
+    ))
+}
+
+/// Object names which parse fine but can not be passed to the kernel
+fn check_name_lengths(segment: &Segment, kind: &'static str, diagnostics: &mut Vec<Diagnostic>) {
+    let mut start = None;
+    for (index, c) in segment
+        .text
+        .char_indices()
+        .chain([(segment.text.len(), ' ')])
+    {
+        match (start, is_sepolicy_char(c)) {
+            (None, true) => start = Some(index),
+            (Some(begin), false) => {
+                let len = index - begin;
+                if len >= SEPOLICY_MAX_LEN {
+                    diagnostics.push(Diagnostic {
+                        severity: Severity::Warning,
+                        line: segment.line,
+                        column: segment.column(begin),
+                        kind: Some(kind),
+                        message: format!(
+                            "name is {len} characters long, at most {} are supported",
+                            SEPOLICY_MAX_LEN - 1
+                        ),
+                    });
+                }
+                start = None;
+            }
+            _ => {}
+        }
+    }
+}
+
+fn diagnose_sepolicy(input: &str, strict: bool) -> Vec<Diagnostic> {
+    // in lenient mode broken statements are dropped, so they are only worth a warning
+    let (severity, suffix) = if strict {
+        (Severity::Error, "")
+    } else {
+        (Severity::Warning, ", statement ignored")
+    };
+    let mut diagnostics = vec![];
+    for segment in split_statements(input) {
+        let keyword_len = segment
+            .text
+            .find(|c| !is_sepolicy_char(c))
+            .unwrap_or(segment.text.len());
+        let Some((kind, fields)) = grammar(&segment.text[..keyword_len]) else {
+            diagnostics.push(Diagnostic {
+                severity,
+                line: segment.line,
+                column: segment.column(0),
+                kind: None,
+                message: format!("unknown statement `{}`{suffix}", token(segment.text)),
+            });
+            continue;
+        };
+        if parse_statement(segment.text).is_some() {
+            check_name_lengths(&segment, kind, &mut diagnostics);
+            continue;
+        }
+        let (offset, message) = locate_error(segment.text, keyword_len, fields)
+            .unwrap_or_else(|| (0, format!("invalid {kind}")));
+        diagnostics.push(Diagnostic {
+            severity,
+            line: segment.line,
+            column: segment.column(offset),
+            kind: Some(kind),
+            message: format!("{message}{suffix}"),
+        });
+    }
+    diagnostics
+}
+
+/// What kind of policy symbol a name in a rule has to be
+#[derive(Clone, Copy)]
+enum Symbol {
+    Type,
+    Attribute,
+    TypeOrAttribute,
+    Class,
+}
+
+impl Symbol {
+    fn describe(self) -> &'static str {
+        match self {
+            Symbol::Type => "type",
+            Symbol::Attribute => "attribute",
+            Symbol::TypeOrAttribute => "type or attribute",
+            Symbol::Class => "class",
+        }
+    }
+}
+
+impl PolicyStatement<'_> {
+    /// Types and attributes this statement creates
+    fn declarations(&self) -> Option<(Symbol, &str)> {
+        match self {
+            PolicyStatement::Type(t) => Some((Symbol::Type, t.name)),
+            PolicyStatement::Attr(a) => Some((Symbol::Attribute, a.name)),
+            _ => None,
+        }
+    }
+
+    /// Every name this statement expects to exist in the policy
+    fn references(&self) -> Vec<(Symbol, &str)> {
+        fn all<'a>(symbol: Symbol, objs: &[&'a str]) -> Vec<(Symbol, &'a str)> {
+            objs.iter().map(|&obj| (symbol, obj)).collect()
+        }
+        match self {
+            PolicyStatement::NormalPerm(perm) => [
+                all(Symbol::TypeOrAttribute, &perm.source),
+                all(Symbol::TypeOrAttribute, &perm.target),
+                all(Symbol::Class, &perm.class),
+            ]
+            .concat(),
+            PolicyStatement::XPerm(perm) => [
+                all(Symbol::TypeOrAttribute, &perm.source),
+                all(Symbol::TypeOrAttribute, &perm.target),
+                all(Symbol::Class, &perm.class),
+            ]
+            .concat(),
+            PolicyStatement::TypeState(state) => all(Symbol::Type, &state.stype),
+            PolicyStatement::Type(t) => all(Symbol::Attribute, &t.attrs),
+            PolicyStatement::TypeAttr(attr) => [
+                all(Symbol::Type, &attr.stype),
+                all(Symbol::Attribute, &attr.sattr),
+            ]
+            .concat(),
+            PolicyStatement::Attr(_) => vec![],
+            PolicyStatement::TypeTransition(trans) => vec![
+                (Symbol::TypeOrAttribute, trans.source),
+                (Symbol::TypeOrAttribute, trans.target),
+                (Symbol::Class, trans.class),
+                (Symbol::Type, trans.default_type),
+            ],
+            PolicyStatement::TypeChange(change) => vec![
+                (Symbol::TypeOrAttribute, change.source),
+                (Symbol::TypeOrAttribute, change.target),
+                (Symbol::Class, change.class),
+                (Symbol::Type, change.default_type),
+            ],
+            // u:object_r:type:s0
+            PolicyStatement::GenFsCon(con) => con
+                .fs_context
+                .split(':')
+                .nth(2)
+                .map(|t| vec![(Symbol::Type, t)])
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// The loaded policy plus whatever the checked rule declares itself
+struct KnownSymbols<'a> {
+    db: &'a PolicyDb,
+    types: BTreeSet<&'a str>,
+    attributes: BTreeSet<&'a str>,
+}
+
+impl<'a> KnownSymbols<'a> {
+    fn contains(&self, symbol: Symbol, name: &str) -> bool {
+        let is_type = self.db.is_type(name) || self.types.contains(name);
+        let is_attr = self.db.is_attribute(name) || self.attributes.contains(name);
+        match symbol {
+            Symbol::Type => is_type,
+            Symbol::Attribute => is_attr,
+            Symbol::TypeOrAttribute => is_type || is_attr,
+            Symbol::Class => self.db.class_perms(name).is_some(),
+        }
+    }
+
+    fn candidates(&self, symbol: Symbol) -> Vec<&'a str> {
+        let types = self.db.types().chain(self.types.iter().copied());
+        let attributes = self.db.attributes().chain(self.attributes.iter().copied());
+        match symbol {
+            Symbol::Type => types.collect(),
+            Symbol::Attribute => attributes.collect(),
+            Symbol::TypeOrAttribute => types.chain(attributes).collect(),
+            Symbol::Class => self.db.classes().collect(),
+        }
+    }
+
+    fn unknown(&self, symbol: Symbol, name: &str) -> String {
+        let mut message = format!("unknown {} `{name}`", symbol.describe());
+        if matches!(symbol, Symbol::Type) && self.contains(Symbol::Attribute, name) {
+            message.push_str(", it is an attribute");
+        } else if matches!(symbol, Symbol::Attribute) && self.contains(Symbol::Type, name) {
+            message.push_str(", it is a type");
+        } else {
+            push_suggestions(&mut message, name, self.candidates(symbol));
+        }
+        message
+    }
+}
+
+fn push_suggestions(message: &mut String, name: &str, candidates: Vec<&str>) {
+    let suggestions = policydb::suggest(name, candidates.into_iter());
+    if !suggestions.is_empty() {
+        let quoted: Vec<String> = suggestions.iter().map(|s| format!("`{s}`")).collect();
+        message.push_str(&format!(", did you mean {}?", quoted.join(" or ")));
+    }
+}
+
+/// Report names in the rule which do not exist in the compiled policy
+fn diagnose_symbols(input: &str, db: &PolicyDb, strict: bool) -> Vec<Diagnostic> {
+    let severity = if strict {
+        Severity::Error
+    } else {
+        Severity::Warning
+    };
+    let statements: Vec<_> = split_statements(input)
+        .into_iter()
+        .filter_map(|segment| parse_statement(segment.text).map(|stmt| (segment, stmt)))
+        .collect();
+
+    let mut known = KnownSymbols {
+        db,
+        types: BTreeSet::new(),
+        attributes: BTreeSet::new(),
+    };
+    for (_, statement) in &statements {
+        match statement.declarations() {
+            Some((Symbol::Type, name)) => known.types.insert(name),
+            Some((_, name)) => known.attributes.insert(name),
+            None => false,
+        };
+    }
+
+    let mut diagnostics = vec![];
+    for (segment, statement) in &statements {
+        let kind = grammar(token(segment.text)).map(|(kind, _)| kind);
+        let mut report = |name: &str, message: String| {
+            let offset = segment.text.find(name).unwrap_or(0);
+            diagnostics.push(Diagnostic {
+                severity,
+                line: segment.line,
+                column: segment.column(offset),
+                kind,
+                message,
+            });
+        };
+        for (symbol, name) in statement.references() {
+            if name != "*" && !known.contains(symbol, name) {
+                report(name, known.unknown(symbol, name));
+            }
+        }
+        let PolicyStatement::NormalPerm(perm) = statement else {
+            continue;
+        };
+        for &class in &perm.class {
+            let Some(class_perms) = db.class_perms(class) else {
+                continue;
+            };
+            for &p in perm.perm.iter().filter(|&&p| p != "*") {
+                if !class_perms.contains(p) {
+                    let mut message = format!("class `{class}` has no permission `{p}`");
+                    push_suggestions(
+                        &mut message,
+                        p,
+                        class_perms.iter().map(String::as_str).collect(),
+                    );
+                    report(p, message);
+                }
+            }
+        }
+    }
+    diagnostics
+}
+
+////////////////////////////////////////////////////////////////
+///  lint, flag rules which weaken the policy more than a module should
+///////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum Risk {
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+impl fmt::Display for Risk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Risk::Low => write!(f, "low"),
+            Risk::Medium => write!(f, "medium"),
+            Risk::High => write!(f, "high"),
+            Risk::Critical => write!(f, "critical"),
+        }
+    }
+}
+
+/// Domains a module has no business granting to or taking from
+const SENSITIVE_DOMAINS: &[&str] = &["untrusted_app", "kernel", "init"];
+
+/// Domains which must only be entered by the platform itself
+const PRIVILEGED_DOMAINS: &[&str] = &[
+    "init",
+    "kernel",
+    "su",
+    "magisk",
+    "vold",
+    "zygote",
+    "system_server",
+    "ueventd",
+];
+
+pub struct LintFinding {
+    pub risk: Risk,
+    line: usize,
+    text: String,
+    message: String,
+}
+
+impl fmt::Display for LintFinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: line {}: {}: {}",
+            self.risk, self.line, self.message, self.text
+        )
+    }
+}
+
+fn lint_statement(statement: &PolicyStatement) -> Vec<(Risk, String)> {
+    let mut findings = vec![];
+    let mut wildcards = |source: &[&str], target: &[&str], class: &[&str], perm: &[&str]| {
+        for (objs, what, risk) in [
+            (source, "source", Risk::Critical),
+            (target, "target", Risk::Critical),
+            (class, "class", Risk::High),
+            (perm, "permission", Risk::Medium),
+        ] {
+            if objs.contains(&"*") {
+                findings.push((risk, format!("wildcard {what}")));
+            }
+        }
+    };
+    match statement {
+        PolicyStatement::NormalPerm(perm) => {
+            wildcards(&perm.source, &perm.target, &perm.class, &perm.perm);
+            if perm.op == "allow" {
+                for &domain in SENSITIVE_DOMAINS {
+                    if perm.source.contains(&domain) || perm.target.contains(&domain) {
+                        findings.push((Risk::High, format!("allow rule involving `{domain}`")));
+                    }
+                }
+            }
+        }
+        PolicyStatement::XPerm(perm) => wildcards(&perm.source, &perm.target, &perm.class, &[]),
+        PolicyStatement::TypeState(state) if state.op == "permissive" => {
+            for domain in &state.stype {
+                findings.push((Risk::High, format!("makes `{domain}` permissive")));
+            }
+        }
+        PolicyStatement::TypeTransition(trans)
+            if PRIVILEGED_DOMAINS.contains(&trans.default_type) =>
+        {
+            findings.push((
+                Risk::Critical,
+                format!("transition into privileged domain `{}`", trans.default_type),
+            ));
+        }
+        PolicyStatement::GenFsCon(con) => findings.push((
+            Risk::Medium,
+            format!("overrides the labels of `{}` files", con.fs_name),
+        )),
+        _ => {}
+    }
+    findings
+}
+
+/// Risky patterns in a rule file, highest risk first; unparsable statements are skipped
+pub fn lint_sepolicy(input: &str) -> Vec<LintFinding> {
+    let mut findings = vec![];
+    for segment in split_statements(input) {
+        let Some(statement) = parse_statement(segment.text) else {
+            continue;
+        };
+        for (risk, message) in lint_statement(&statement) {
+            findings.push(LintFinding {
+                risk,
+                line: segment.line,
+                text: segment.text.to_string(),
+                message,
+            });
+        }
+    }
+    findings.sort_by_key(|finding| std::cmp::Reverse(finding.risk));
+    findings
+}
+
+/// Problems of a module's sepolicy.rule, which is loaded leniently, as `line N: message`
+pub fn module_rule_warnings(input: &str) -> Vec<String> {
+    diagnose_sepolicy(input, false)
+        .iter()
+        .map(|diagnostic| match diagnostic.kind {
+            Some(kind) => format!("line {}: [{kind}] {}", diagnostic.line, diagnostic.message),
+            None => format!("line {}: {}", diagnostic.line, diagnostic.message),
+        })
+        .collect()
+}
+
+/// Risk at which module installation is refused, None unless the user opted in
+pub fn lint_block_level() -> Option<Risk> {
+    let level = std::fs::read_to_string(defs::SEPOLICY_LINT_BLOCK_FILE).ok()?;
+    match clap::ValueEnum::from_str(level.trim(), true) {
+        Ok(risk) => Some(risk),
+        Err(e) => {
+            warn!("invalid sepolicy lint block level: {e}");
+            None
+        }
+    }
+}
+
+pub fn set_lint_block_level(level: Option<Risk>) -> Result<()> {
+    match level {
+        Some(risk) => std::fs::write(defs::SEPOLICY_LINT_BLOCK_FILE, risk.to_string())?,
+        None if Path::new(defs::SEPOLICY_LINT_BLOCK_FILE).exists() => {
+            std::fs::remove_file(defs::SEPOLICY_LINT_BLOCK_FILE)?
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+pub fn lint_rule(policy: &str) -> Result<()> {
+    let policy = read_policy(policy)?;
+    let findings = lint_sepolicy(&policy);
+    for finding in &findings {
+        println!("{finding}");
+    }
+    println!("{} findings", findings.len());
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////
+///  policy backends, where expanded statements are submitted
+///////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum PolicyBackendKind {
+    /// Kernel if it supports sepolicy supercall, magiskpolicy otherwise
+    Auto,
+    /// Submit every statement to the kernel through supercall
+    Kernel,
+    /// Batch all statements into a single `magiskpolicy --live` run
+    Magiskpolicy,
+    /// Only print the expanded statements
+    DryRun,
+}
+
+trait PolicyBackend {
+    fn name(&self) -> &'static str;
+
+    /// submit a single atomic statement, failures are reported per statement
+    fn apply(&mut self, statement: &AtomicStatement) -> Result<()>;
+
+    /// flush whatever `apply` has queued
+    fn commit(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct KernelBackend {
+    key: CString,
+}
+
+impl PolicyBackend for KernelBackend {
+    fn name(&self) -> &'static str {
+        "kernel"
+    }
+
+    fn apply(&mut self, statement: &AtomicStatement) -> Result<()> {
+        let policy = FfiPolicy::from(statement);
+        let result = supercall::sc_set_sepolicy(&self.key, (&raw const policy).cast());
+        ensure!(
+            result == 0,
+            "kernel rejected statement: {}",
+            std::io::Error::last_os_error()
+        );
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct MagiskPolicyBackend {
+    statements: Vec<String>,
+}
+
+impl PolicyBackend for MagiskPolicyBackend {
+    fn name(&self) -> &'static str {
+        "magiskpolicy"
+    }
+
+    fn apply(&mut self, statement: &AtomicStatement) -> Result<()> {
+        self.statements.push(statement.to_string());
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        if self.statements.is_empty() {
+            return Ok(());
+        }
+        let rule_file = Path::new(defs::SEPOLICY_BATCH_FILE);
+        std::fs::write(rule_file, self.statements.join("\n"))
+            .with_context(|| format!("Failed to write {}", rule_file.display()))?;
+        let status = Command::new(assets::MAGISKPOLICY_PATH)
+            .arg("--live")
+            .arg("--apply")
+            .arg(rule_file)
+            .status()
+            .with_context(|| format!("Failed to exec {}", assets::MAGISKPOLICY_PATH));
+        let _ = std::fs::remove_file(rule_file);
+        ensure!(
+            status?.success(),
+            "magiskpolicy failed to apply {} statements",
+            self.statements.len()
+        );
+        self.statements.clear();
+        Ok(())
+    }
+}
+
+struct DryRunBackend;
+
+impl PolicyBackend for DryRunBackend {
+    fn name(&self) -> &'static str {
+        "dry-run"
+    }
+
+    fn apply(&mut self, statement: &AtomicStatement) -> Result<()> {
+        println!("{statement}");
+        Ok(())
+    }
+}
+
+fn new_backend(
+    kind: PolicyBackendKind,
+    superkey: &Option<String>,
+) -> Result<Box<dyn PolicyBackend>> {
+    let key = superkey.as_deref().and_then(|key| CString::new(key).ok());
+    let backend: Box<dyn PolicyBackend> = match (kind, key) {
+        (PolicyBackendKind::DryRun, _) => Box::new(DryRunBackend),
+        (PolicyBackendKind::Magiskpolicy, _) => Box::new(MagiskPolicyBackend::default()),
+        (PolicyBackendKind::Kernel, Some(key)) => Box::new(KernelBackend { key }),
+        (PolicyBackendKind::Kernel, None) => bail!("kernel backend requires a superkey"),
+        (PolicyBackendKind::Auto, Some(key)) if supercall::sc_sepolicy_supported(&key) => {
+            Box::new(KernelBackend { key })
+        }
+        (PolicyBackendKind::Auto, _) => Box::new(MagiskPolicyBackend::default()),
+    };
+    info!("sepolicy backend: {}", backend.name());
+    Ok(backend)
+}
+
+#[derive(Default)]
+struct ApplyReport {
+    applied: usize,
+    failures: Vec<String>,
+}
+
+impl ApplyReport {
+    fn submit(
+        &mut self,
+        backend: &mut dyn PolicyBackend,
+        origin: &str,
+        statements: &[PolicyStatement],
+    ) {
+        for statement in statements {
+            let atomics: Vec<AtomicStatement> = match statement.try_into() {
+                Ok(atomics) => atomics,
+                Err(e) => {
+                    self.failures.push(format!("{origin}: {statement:?}: {e}"));
+                    continue;
+                }
+            };
+            for atomic in &atomics {
+                match backend.apply(atomic) {
+                    Ok(()) => self.applied += 1,
+                    Err(e) => self.failures.push(format!("{origin}: {atomic}: {e}")),
+                }
+            }
+        }
+    }
+
+    fn finish(self, backend: &mut dyn PolicyBackend) -> Result<()> {
+        backend.commit()?;
+        info!(
+            "applied {} sepolicy statements via {}",
+            self.applied,
+            backend.name()
+        );
+        if self.failures.is_empty() {
+            return Ok(());
+        }
+        for failure in &self.failures {
+            warn!("sepolicy: {failure}");
+        }
+        bail!(
+            "{} sepolicy statements failed:\n{}",
+            self.failures.len(),
+            self.failures.join("\n")
+        )
+    }
+}
+
+fn read_policy(policy: &str) -> Result<String> {
+    let path = Path::new(policy);
+    if path.exists() {
+        Ok(std::fs::read_to_string(path)?)
+    } else {
+        Ok(policy.to_string())
+    }
+}
+
 pub fn check_rule(policy: &str) -> Result<()> {
+    let policy = read_policy(policy)?;
+    parse_sepolicy(policy.trim(), true)?;
+    Ok(())
+}
+
+/// Report every problem in a rule as `file:line:column: severity: message`,
+/// optionally checking the names it uses against a compiled policy
+pub fn check_rule_diagnostics(policy: &str, strict: bool, against: Option<&Path>) -> Result<()> {
     let path = Path::new(policy);
-    let policy = if path.exists() {
-        std::fs::read_to_string(path)?
+    let (name, content) = if path.exists() {
+        (policy, std::fs::read_to_string(path)?)
     } else {
-        policy.to_string()
+        ("<input>", policy.to_string())
     };
-    parse_sepolicy(policy.trim(), true)?;
+    let mut diagnostics = diagnose_sepolicy(&content, strict);
+    if let Some(against) = against {
+        let db = PolicyDb::load(against)?;
+        info!("checking against {}: {}", against.display(), db.summary());
+        diagnostics.extend(diagnose_symbols(&content, &db, strict));
+        diagnostics.sort_by_key(|d| (d.line, d.column));
+    }
+    for diagnostic in &diagnostics {
+        println!("{name}:{diagnostic}");
+    }
+    let errors = diagnostics
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+        .count();
+    let warnings = diagnostics.len() - errors;
+    ensure!(errors == 0, "{errors} errors, {warnings} warnings");
+    println!("0 errors, {warnings} warnings");
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ParsedStatement<'a> {
+    line: usize,
+    #[serde(flatten)]
+    statement: PolicyStatement<'a>,
+}
+
+/// Print the syntax tree of every statement, as JSON or in debug form
+pub fn parse_rule(policy: &str, json: bool) -> Result<()> {
+    let policy = read_policy(policy)?;
+    let mut parsed = vec![];
+    for segment in split_statements(&policy) {
+        let Some(statement) = parse_statement(segment.text) else {
+            bail!(
+                "Failed to parse policy statement at line {}: {}",
+                segment.line,
+                segment.text
+            )
+        };
+        parsed.push(ParsedStatement {
+            line: segment.line,
+            statement,
+        });
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&parsed)?);
+    } else {
+        for ParsedStatement { line, statement } in &parsed {
+            println!("{line}: {statement:?}");
+        }
+    }
+    Ok(())
+}
+
+/// Canonical form of a rule file: one statement per line, comments kept, runs of blank lines collapsed
+fn format_sepolicy(input: &str) -> Result<String> {
+    let mut output = String::new();
+    let mut blank = false;
+    for (index, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            blank = !output.is_empty();
+            continue;
+        }
+        if blank {
+            output.push('\n');
+            blank = false;
+        }
+        for part in line.split(';').map(str::trim).filter(|p| !p.is_empty()) {
+            let code = strip_comment(part).trim_end();
+            if !code.is_empty() {
+                let Some(statement) = parse_statement(code) else {
+                    bail!(
+                        "Failed to parse policy statement at line {}: {}",
+                        index + 1,
+                        code
+                    )
+                };
+                output.push_str(&statement.to_string());
+            }
+            if let Some(comment) = part.get(code.len()..).filter(|c| !c.is_empty()) {
+                if !code.is_empty() {
+                    output.push(' ');
+                }
+                output.push_str(comment.trim_start());
+            }
+            output.push('\n');
+        }
+    }
+
+    let before = parse_sepolicy(input, true)?;
+    let after = parse_sepolicy(&output, true)?;
+    ensure!(
+        before == after,
+        "formatting changed the meaning of the rules, please report this"
+    );
+    Ok(output)
+}
+
+/// Print a rule file in canonical form, or rewrite it in place
+pub fn format_rule(file: &Path, write: bool, check: bool) -> Result<()> {
+    let content = std::fs::read_to_string(file)
+        .with_context(|| format!("Failed to read {}", file.display()))?;
+    let formatted = format_sepolicy(&content)?;
+    if check {
+        ensure!(formatted == content, "{} is not formatted", file.display());
+    } else if write {
+        if formatted != content {
+            std::fs::write(file, formatted)?;
+        }
+    } else {
+        print!("{formatted}");
+    }
+    Ok(())
+}
+
+pub fn apply_rule(policy: &str, kind: PolicyBackendKind, superkey: &Option<String>) -> Result<()> {
+    let policy = read_policy(policy)?;
+    let statements = parse_sepolicy(policy.trim(), true)?;
+    let mut backend = new_backend(kind, superkey)?;
+    let mut report = ApplyReport::default();
+    report.submit(backend.as_mut(), "cli", &statements);
+    report.finish(backend.as_mut())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum DumpFormat {
+    /// one statement per line, prefixed by the modules that add it
+    Text,
+    /// CIL, grouped by contributing modules
+    Cil,
+    /// a rule file magiskpolicy can load, grouped by contributing modules
+    Magiskpolicy,
+}
+
+/// Print the deduplicated atomic statements of every given module and who contributed them
+pub fn dump_module_rules(rules: &[(String, PathBuf)], format: DumpFormat) -> Result<()> {
+    let mut merged: BTreeMap<String, (AtomicStatement, BTreeSet<&str>)> = BTreeMap::new();
+    let mut total = 0;
+    for (id, rule_file) in rules {
+        let content = match std::fs::read_to_string(rule_file) {
+            Ok(content) => content,
+            Err(e) => {
+                warn!("{id}: {e}");
+                continue;
+            }
+        };
+        for statement in parse_sepolicy(&content, false)? {
+            let atomics: Vec<AtomicStatement> = match (&statement).try_into() {
+                Ok(atomics) => atomics,
+                Err(e) => {
+                    warn!("{id}: {statement:?}: {e}");
+                    continue;
+                }
+            };
+            for atomic in atomics {
+                total += 1;
+                merged
+                    .entry(atomic.to_string())
+                    .or_insert_with(|| (atomic, BTreeSet::new()))
+                    .1
+                    .insert(id);
+            }
+        }
+    }
+
+    if format == DumpFormat::Text {
+        for (rendered, (_, ids)) in &merged {
+            let ids: Vec<&str> = ids.iter().copied().collect();
+            println!("[{}] {rendered}", ids.join(", "));
+        }
+        println!(
+            "# {} statements from {} modules, {} duplicates",
+            merged.len(),
+            rules.len(),
+            total - merged.len()
+        );
+        return Ok(());
+    }
+
+    let mut groups: BTreeMap<&BTreeSet<&str>, Vec<&AtomicStatement>> = BTreeMap::new();
+    for (atomic, ids) in merged.values() {
+        groups.entry(ids).or_default().push(atomic);
+    }
+    let comment = if format == DumpFormat::Cil { ";" } else { "#" };
+    let mut emitted = BTreeSet::new();
+    for (ids, atomics) in groups {
+        let ids: Vec<&str> = ids.iter().copied().collect();
+        println!("{comment} {}", ids.join(", "));
+        for atomic in atomics {
+            if format == DumpFormat::Magiskpolicy {
+                println!("{atomic}");
+                continue;
+            }
+            match atomic.to_cil() {
+                // a type with several attributes declares itself once
+                Some(lines) => lines
+                    .into_iter()
+                    .filter(|line| emitted.insert(line.clone()))
+                    .for_each(|line| println!("{line}")),
+                None => println!("; not expressible in CIL: {atomic}"),
+            }
+        }
+    }
     Ok(())
 }
+
+/// Apply the sepolicy.rule of every given module through one backend, in a single batch
+pub fn apply_module_rules(rules: &[(String, PathBuf)], superkey: &Option<String>) -> Result<()> {
+    let mut backend = new_backend(PolicyBackendKind::Auto, superkey)?;
+    let mut report = ApplyReport::default();
+    for (id, rule_file) in rules {
+        info!("load policy: {}", rule_file.display());
+        let content = match std::fs::read_to_string(rule_file) {
+            Ok(content) => content,
+            Err(e) => {
+                report.failures.push(format!("{id}: {e}"));
+                continue;
+            }
+        };
+        // a bad line should not drop the rest of the module's rules
+        let statements = parse_sepolicy(&content, false)?;
+        report.submit(backend.as_mut(), id, &statements);
+    }
+    report.finish(backend.as_mut())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture() -> PolicyDb {
+        PolicyDb::parse(include_bytes!("../tests/fixtures/policy.30")).unwrap()
+    }
+
+    /// Formatting keeps the parsed statements and formats its own output unchanged
+    fn assert_round_trip(input: &str) {
+        let formatted = format_sepolicy(input).unwrap();
+        assert_eq!(
+            parse_sepolicy(input, true).unwrap(),
+            parse_sepolicy(&formatted, true).unwrap(),
+            "{formatted}"
+        );
+        assert_eq!(format_sepolicy(&formatted).unwrap(), formatted);
+    }
+
+    /// A brace list whose words and single spaces take exactly `len` chars
+    fn brace_words(len: usize) -> String {
+        let mut words = vec![];
+        let mut taken = 0;
+        while taken + 10 < len {
+            words.push("abcdefghi".to_string());
+            taken += 10;
+        }
+        words.push("z".repeat(len - taken));
+        words.join(" ")
+    }
+
+    #[test]
+    fn brace_lists_near_the_length_limit() {
+        for len in 96..=BRACE_LIST_MAX_LEN {
+            let words = brace_words(len);
+            assert_eq!(words.len(), len);
+            assert_round_trip(&format!("allow shell system_file file {{{words}}}"));
+            assert_round_trip(&format!("allow {{{words}}} system_file file read"));
+        }
+    }
+
+    #[test]
+    fn xperm_round_trip() {
+        assert_round_trip("allowxperm shell system_file file ioctl 0x1234");
+        assert_round_trip("dontauditxperm { shell init } system_file file ioctl 0x8910-0x8920");
+    }
+
+    #[test]
+    fn type_round_trip() {
+        assert_round_trip("type x");
+        assert_round_trip("type x file_type");
+        assert_round_trip("type x { file_type domain }");
+    }
+
+    #[test]
+    fn alias_round_trip() {
+        assert_round_trip("name_transition shell system_file file app_data_file name");
+        assert_round_trip("type_transition shell system_file file app_data_file");
+        assert_round_trip("attradd shell domain");
+        assert_round_trip("typeattribute shell { domain file_type }");
+    }
+
+    #[test]
+    fn comment_round_trip() {
+        assert_round_trip(
+            "# leading comment\n\nallow shell system_file file read # trailing\n\n\n\
+             allow init shell dir search; allow init shell file read # after two\n# last",
+        );
+    }
+
+    #[test]
+    fn known_names_pass() {
+        let rule = "allow shell system_file file { read execute }\n\
+                    allow domain app_data_file dir search";
+        assert!(diagnose_symbols(rule, &fixture(), true).is_empty());
+    }
+
+    #[test]
+    fn unknown_names_are_reported() {
+        let rule = "allow shel system_file file read\nallow shell system_file dir exec";
+        let diagnostics: Vec<String> = diagnose_symbols(rule, &fixture(), true)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            diagnostics,
+            [
+                "1:7: error: [permission rule] unknown type or attribute `shel`, did you mean `shell`?",
+                "2:29: error: [permission rule] class `dir` has no permission `exec`",
+            ]
+        );
+    }
+}
@@ -10,7 +10,9 @@ use std::{
 };
 
 use errno::errno;
-use libc::{EINVAL, c_int, c_long, c_void, execv, fork, pid_t, setenv, syscall, uid_t, wait};
+use libc::{
+    EINVAL, ENOSYS, c_int, c_long, c_void, execv, fork, pid_t, setenv, syscall, uid_t, wait,
+};
 use log::{error, info, warn};
 
 use crate::package::{read_ap_package_config, synchronize_package_uid};
@@ -33,6 +35,7 @@ const SUPERCALL_SU_NUMS: c_long = 0x1102;
 const SUPERCALL_SU_LIST: c_long = 0x1103;
 const SUPERCALL_SU_RESET_PATH: c_long = 0x1111;
 const SUPERCALL_SU_GET_SAFEMODE: c_long = 0x1112;
+const SUPERCALL_SEPOLICY: c_long = 0x1120;
 
 const SUPERCALL_SCONTEXT_LEN: usize = 0x60;
 
@@ -132,6 +135,37 @@ pub fn sc_su_get_safemode(key: &CStr) -> c_long {
     }
 }
 
+pub fn sc_set_sepolicy(key: &CStr, policy: *const c_void) -> c_long {
+    if key.to_bytes().is_empty() || policy.is_null() {
+        return (-EINVAL).into();
+    }
+    unsafe {
+        syscall(
+            __NR_SUPERCALL,
+            key.as_ptr(),
+            ver_and_cmd(SUPERCALL_SEPOLICY),
+            policy,
+        ) as c_long
+    }
+}
+
+/// Kernels without sepolicy support fail unknown supercalls with ENOSYS, a supporting
+/// kernel rejects the null policy with another errno
+pub fn sc_sepolicy_supported(key: &CStr) -> bool {
+    if key.to_bytes().is_empty() {
+        return false;
+    }
+    let result = unsafe {
+        syscall(
+            __NR_SUPERCALL,
+            key.as_ptr(),
+            ver_and_cmd(SUPERCALL_SEPOLICY),
+            ptr::null::<c_void>(),
+        ) as c_long
+    };
+    !(result == -1 && errno().0 == ENOSYS)
+}
+
 fn sc_su(key: &CStr, profile: &SuProfile) -> c_long {
     if key.to_bytes().is_empty() {
         return (-EINVAL).into();
@@ -161,3 +161,147 @@ pub fn get_tmp_path() -> &'static str {
     }
     ""
 }
+
+/// Total size of the files below `dir`
+pub fn dir_size<T: AsRef<Path>>(dir: T) -> u64 {
+    walkdir::WalkDir::new(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Copy every extended attribute, which holds SELinux labels and overlayfs' opaque markers
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn copy_xattrs(from: &Path, to: &Path) -> Result<()> {
+    use extattr::{Flags, lgetxattr, llistxattr, lsetxattr};
+
+    let names = match llistxattr(from) {
+        Result::Ok(names) => names,
+        Err(e) if e.0 == libc::ENOTSUP => return Ok(()),
+        Err(e) => {
+            return Err(Error::from(e))
+                .with_context(|| format!("Failed to list xattrs of {}", from.display()));
+        }
+    };
+    for name in names {
+        let value = lgetxattr(from, &name)?;
+        lsetxattr(to, &name, value, Flags::empty()).with_context(|| {
+            format!(
+                "Failed to copy {} of {}",
+                name.to_string_lossy(),
+                from.display()
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// Copy a tree keeping modes, owners, symlinks, device nodes and extended attributes,
+/// so whiteouts and opaque dirs of modules survive it
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn copy_dir_all<S: AsRef<Path>, D: AsRef<Path>>(src: S, dst: D) -> Result<()> {
+    use std::os::unix::fs::{MetadataExt, lchown, symlink};
+
+    use rustix::fs::{CWD, FileType, Mode, mknodat};
+
+    let (src, dst) = (src.as_ref(), dst.as_ref());
+    ensure_dir_exists(dst)?;
+    let copy_attrs = |from: &Path, to: &Path| -> Result<()> {
+        let meta = std::fs::symlink_metadata(from)?;
+        lchown(to, Some(meta.uid()), Some(meta.gid()))?;
+        if !meta.file_type().is_symlink() {
+            set_permissions(to, meta.permissions())?;
+        }
+        copy_xattrs(from, to)
+    };
+    copy_attrs(src, dst)?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let (from, to) = (entry.path(), dst.join(entry.file_name()));
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            copy_dir_all(&from, &to)?;
+            continue;
+        }
+        if file_type.is_symlink() {
+            symlink(std::fs::read_link(&from)?, &to)?;
+        } else if file_type.is_file() {
+            std::fs::copy(&from, &to)
+                .with_context(|| format!("Failed to copy {}", from.display()))?;
+        } else {
+            // device nodes, whiteouts among them, fifos and sockets
+            let meta = entry.metadata()?;
+            mknodat(
+                CWD,
+                &to,
+                FileType::from_raw_mode(meta.mode()),
+                Mode::from_raw_mode(meta.mode()),
+                meta.rdev(),
+            )
+            .with_context(|| format!("Failed to copy {}", from.display()))?;
+        }
+        copy_attrs(&from, &to)?;
+    }
+    Ok(())
+}
+
+/// Rename a tree, copying it when it has to cross filesystems, like onto a module image
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn move_dir<S: AsRef<Path>, D: AsRef<Path>>(src: S, dst: D) -> Result<()> {
+    let (src, dst) = (src.as_ref(), dst.as_ref());
+    match std::fs::rename(src, dst) {
+        Result::Ok(()) => Ok(()),
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+            copy_dir_all(src, dst)?;
+            std::fs::remove_dir_all(src)?;
+            Ok(())
+        }
+        Err(e) => Err(Error::from(e))
+            .with_context(|| format!("Failed to move {} to {}", src.display(), dst.display())),
+    }
+}
+
+/// Add a directory tree to a zip below `prefix`, keeping modes and symlinks
+#[cfg(unix)]
+pub fn add_zip_tree<W: Write + std::io::Seek>(
+    zip: &mut zip::ZipWriter<W>,
+    dir: &Path,
+    prefix: &str,
+    skip: &[&str],
+) -> Result<()> {
+    let options = zip::write::SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated);
+    for entry in walkdir::WalkDir::new(dir).min_depth(1).sort_by_file_name() {
+        let entry = entry?;
+        let relative = entry
+            .path()
+            .strip_prefix(dir)?
+            .to_string_lossy()
+            .to_string();
+        if skip.contains(&relative.as_str()) {
+            continue;
+        }
+        let name = if prefix.is_empty() {
+            relative
+        } else {
+            format!("{prefix}/{relative}")
+        };
+        let metadata = entry.path().symlink_metadata()?;
+        let options = options.unix_permissions(metadata.permissions().mode() & 0o7777);
+        let file_type = entry.file_type();
+        if file_type.is_symlink() {
+            let target = std::fs::read_link(entry.path())?;
+            zip.add_symlink(name, target.to_string_lossy(), options)?;
+        } else if file_type.is_dir() {
+            zip.add_directory(name, options)?;
+        } else if file_type.is_file() {
+            zip.start_file(name, options)?;
+            std::io::copy(&mut File::open(entry.path())?, zip)?;
+        } else {
+            warn!("skip special file {}", entry.path().display());
+        }
+    }
+    Ok(())
+}